@@ -0,0 +1,37 @@
+use crate::config::AlertConfig;
+
+// Fires once when the count reaches the threshold. It only fires again
+// after the count dropped below `rearm_below`, so a crowd hovering around
+// the threshold doesn't raise an alert on every pass.
+pub struct AlertMonitor {
+    threshold: usize,
+    rearm_below: usize,
+    armed: bool,
+}
+
+impl AlertMonitor {
+    pub fn new(config: &AlertConfig) -> Option<Self> {
+        let threshold = config.threshold?;
+        Some(AlertMonitor {
+            threshold,
+            rearm_below: config.rearm_below.unwrap_or(threshold),
+            armed: true,
+        })
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    // True when this count raises the alert
+    pub fn update(&mut self, people_count: usize) -> bool {
+        if people_count < self.rearm_below {
+            self.armed = true;
+        }
+        if self.armed && people_count >= self.threshold {
+            self.armed = false;
+            return true;
+        }
+        false
+    }
+}
@@ -0,0 +1,93 @@
+use opencv::{core::Mat, prelude::*};
+use std::time::{Duration, Instant};
+
+use crate::camera::Camera;
+use crate::config::Config;
+use crate::detector::create_detector;
+use crate::error::AppError;
+use crate::{metrics, zone};
+
+// Runs the configured detector on `frames` frames of the configured source
+// and prints how long it took, without MQTT or a preview window. Every frame
+// read is detected, so the achieved rate is what the Pi manages end to end.
+pub async fn run(config: &Config, frames: u64) -> Result<(), AppError> {
+    let mut cam = Camera::open_with_retry(&config.camera)
+        .await
+        .map_err(AppError::Camera)?;
+    let mut detector = create_detector(config).map_err(AppError::Detection)?;
+    log::info!("Benchmarking {} frames from {}", frames, config.camera.source);
+
+    let mut latencies = Vec::with_capacity(frames as usize);
+    let mut detections: u64 = 0;
+    let mut frame = Mat::default();
+    // Of the last frame read, a finished file leaves `frame` empty
+    let mut size = (0, 0);
+    // After loading the model, so growth during the run points at a leak per frame
+    let rss_start = metrics::resident_memory_bytes();
+    let started = Instant::now();
+    while (latencies.len() as u64) < frames {
+        if !cam.read(&mut frame).map_err(AppError::Camera)? {
+            if cam.is_finished() {
+                log::warn!("The source ended after {} frames", latencies.len());
+                break;
+            }
+            if cam.is_stalled() {
+                cam.reconnect(None).map_err(AppError::Camera)?;
+            } else {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            continue;
+        }
+        let detection_started = Instant::now();
+        let boxes = zone::filter(&config.zones, &detector.detect(&frame).map_err(AppError::Detection)?);
+        latencies.push(detection_started.elapsed());
+        detections += boxes.len() as u64;
+        size = (frame.cols(), frame.rows());
+    }
+    let elapsed = started.elapsed();
+    let rss_end = metrics::resident_memory_bytes();
+    cam.release().map_err(AppError::Camera)?;
+
+    print_results(&mut latencies, detections, elapsed, size);
+    // None without /proc
+    if let (Some(start), Some(end)) = (rss_start, rss_end) {
+        println!(
+            "Resident memory:       {:.1} MB at start, {:.1} MB at end ({:+.1} MB)",
+            megabytes(start),
+            megabytes(end),
+            megabytes(end) - megabytes(start)
+        );
+    }
+    Ok(())
+}
+
+fn print_results(latencies: &mut [Duration], detections: u64, elapsed: Duration, (width, height): (i32, i32)) {
+    println!("Frames processed:      {}", latencies.len());
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort_unstable();
+    let average = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+    // Nearest rank, the slowest pass for fewer than 20 frames
+    let rank = (latencies.len() * 95).div_ceil(100);
+    let p95 = latencies[rank.max(1) - 1];
+
+    println!("Frame size:            {}x{}", width, height);
+    println!("Average detection:     {:.1} ms", millis(average));
+    println!("Minimum detection:     {:.1} ms", millis(latencies[0]));
+    println!("Maximum detection:     {:.1} ms", millis(latencies[latencies.len() - 1]));
+    println!("95th percentile:       {:.1} ms", millis(p95));
+    println!(
+        "Achieved rate:         {:.1} FPS",
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!("People detections:     {}", detections);
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn megabytes(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
@@ -0,0 +1,154 @@
+use rumqttc::{AsyncClient, Publish, QoS};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::config::CameraControls;
+use crate::mqtt::Topics;
+
+// Commands not yet picked up by the detection loop
+const COMMAND_QUEUE_CAPACITY: usize = 8;
+
+// Runtime control received on the command topic, e.g. `{"command":"pause"}`
+// or `{"command":"set_interval","ms":500}`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Command {
+    Pause,
+    Resume,
+    // Save the next annotated frame as a JPEG
+    Snapshot,
+    // Minimum time between two detection passes, 0 detects on every frame
+    SetInterval { ms: u64 },
+    // Applied by the capture thread, e.g. `{"command":"set_camera","exposure":150}`
+    SetCamera(CameraControls),
+    // Zero the line crossing totals, see `line_crossing::LineCounter`
+    ResetCrossings,
+}
+
+impl Command {
+    // As written in the `command` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Pause => "pause",
+            Command::Resume => "resume",
+            Command::Snapshot => "snapshot",
+            Command::SetInterval { .. } => "set_interval",
+            Command::SetCamera(_) => "set_camera",
+            Command::ResetCrossings => "reset_crossings",
+        }
+    }
+}
+
+// Published to the ack topic for every message on the command topic
+#[derive(Debug, Serialize)]
+struct Ack {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<&'static str>,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Handles the command topic on the MQTT event loop task and hands parsed
+// commands to the detection loop
+pub struct CommandTopic {
+    topic: String,
+    ack_topic: String,
+    commands: mpsc::Sender<Command>,
+}
+
+impl CommandTopic {
+    pub fn new(topics: &Topics) -> (Self, mpsc::Receiver<Command>) {
+        let (commands, receiver) = mpsc::channel(COMMAND_QUEUE_CAPACITY);
+        let topic = CommandTopic {
+            topic: topics.command.clone(),
+            ack_topic: topics.command_ack.clone(),
+            commands,
+        };
+        (topic, receiver)
+    }
+
+    // The broker forgets subscriptions of a clean session, so this runs after every connect
+    pub fn subscribe(&self, client: &AsyncClient) {
+        if let Err(e) = client.try_subscribe(&self.topic, QoS::AtLeastOnce) {
+            log::warn!("Failed to subscribe to {}: {}", self.topic, e);
+        }
+    }
+
+    pub fn handle(&self, client: &AsyncClient, publish: &Publish) {
+        if publish.topic != self.topic {
+            return;
+        }
+
+        let ack = match serde_json::from_slice::<Command>(&publish.payload) {
+            Ok(command) => {
+                let name = command.name();
+                log::info!("Received command {:?}", command);
+                match self.commands.try_send(command) {
+                    Ok(()) => Ack {
+                        command: Some(name),
+                        success: true,
+                        error: None,
+                    },
+                    Err(e) => Ack {
+                        command: Some(name),
+                        success: false,
+                        error: Some(format!("command not accepted: {}", e)),
+                    },
+                }
+            }
+            Err(e) => {
+                log::warn!("Ignoring invalid command on {}: {}", self.topic, e);
+                Ack {
+                    command: None,
+                    success: false,
+                    error: Some(e.to_string()),
+                }
+            }
+        };
+
+        let payload = match serde_json::to_string(&ack) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::warn!("Failed to serialize command ack: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = client.try_publish(&self.ack_topic, QoS::AtLeastOnce, false, payload) {
+            log::warn!("Failed to publish command ack to {}: {}", self.ack_topic, e);
+        }
+    }
+}
+
+// State the detection loop keeps from the commands it received
+#[derive(Debug, Default)]
+pub struct Controls {
+    pub paused: bool,
+    pub snapshot_requested: bool,
+    pub detection_interval: Duration,
+}
+
+impl Controls {
+    pub fn apply(&mut self, command: Command) {
+        match command {
+            Command::Pause => {
+                self.paused = true;
+                log::info!("Detection paused");
+            }
+            Command::Resume => {
+                self.paused = false;
+                log::info!("Detection resumed");
+            }
+            Command::Snapshot => self.snapshot_requested = true,
+            Command::SetInterval { ms } => {
+                self.detection_interval = Duration::from_millis(ms);
+                log::info!("Detection interval set to {:?}", self.detection_interval);
+            }
+            // Forwarded to the capture thread before it gets here
+            Command::SetCamera(_) => {}
+            // Handled by the detection loop, which owns the line counter
+            Command::ResetCrossings => {}
+        }
+    }
+}
@@ -0,0 +1,1984 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::camera::CameraSource;
+use crate::preprocessing::{self, PreprocessStep};
+use crate::zone::Zone;
+
+// Built-in defaults, every user config is merged on top of this
+const DEFAULT_CONFIG: &str = include_str!("../default_config.toml");
+
+// Values never written to the log
+const SECRET_KEYS: &[&str] = &["broker.password", "influxdb.token", "s3_upload.secret_access_key"];
+
+/// Real-time people detection publishing counts over MQTT.
+///
+/// Flags override values from the config file, which override the built-in defaults.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path to a TOML config file
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Video device index or stream URL, e.g. rtsp://192.168.1.20:554/stream (default: 2)
+    #[arg(long, global = true, visible_alias = "source", alias = "camera-index", value_name = "INDEX|URL")]
+    pub camera_source: Option<CameraSource>,
+
+    /// GStreamer pipeline ending in appsink to capture from instead of --camera-source,
+    /// e.g. for the Pi camera module: "libcamerasrc ! video/x-raw,width=1280,height=720 ! videoconvert ! appsink"
+    #[arg(long, global = true, value_name = "PIPELINE", conflicts_with = "camera_source")]
+    pub gst_pipeline: Option<String>,
+
+    /// People detector to run (default: hog)
+    #[arg(long, global = true, value_enum, value_name = "DETECTOR")]
+    pub detector: Option<DetectorType>,
+
+    /// HOG hit threshold, lower finds more people and more false positives (default: 0.88)
+    #[arg(long, global = true, value_name = "VALUE")]
+    pub hog_hit_threshold: Option<f64>,
+
+    /// HOG window stride in pixels (default: 8x8)
+    #[arg(long, global = true, value_name = "WxH", value_parser = parse_size)]
+    pub hog_win_stride: Option<(i32, i32)>,
+
+    /// HOG padding in pixels (default: 26x26)
+    #[arg(long, global = true, value_name = "WxH", value_parser = parse_size)]
+    pub hog_padding: Option<(i32, i32)>,
+
+    /// HOG scale step between detection window sizes (default: 1.03)
+    #[arg(long, global = true, value_name = "FACTOR")]
+    pub hog_scale: Option<f64>,
+
+    /// HOG grouping threshold for overlapping boxes (default: 2)
+    #[arg(long, global = true, value_name = "VALUE")]
+    pub hog_group_threshold: Option<f64>,
+
+    /// Merge HOG boxes overlapping by more than this intersection over union, 1.0 keeps all (default: 0.4)
+    #[arg(long, global = true, value_name = "IOU")]
+    pub hog_nms_threshold: Option<f64>,
+
+    /// Downscale frames to this width before running HOG, keeping the aspect ratio unless --detect-height is given
+    #[arg(long, global = true, value_name = "PIXELS")]
+    pub detect_width: Option<i32>,
+
+    /// Downscale frames to this height before running HOG, keeping the aspect ratio unless --detect-width is given
+    #[arg(long, global = true, value_name = "PIXELS")]
+    pub detect_height: Option<i32>,
+
+    /// Downscale frames by this factor before running HOG, e.g. 0.5
+    #[arg(long, global = true, value_name = "FACTOR", conflicts_with_all = ["detect_width", "detect_height"])]
+    pub detect_scale: Option<f64>,
+
+    /// Requested frame width in pixels (default: 1280)
+    #[arg(long, global = true, value_name = "PIXELS")]
+    pub width: Option<f64>,
+
+    /// Requested frame height in pixels (default: 720)
+    #[arg(long, global = true, value_name = "PIXELS")]
+    pub height: Option<f64>,
+
+    /// Requested capture rate in frames per second, the driver may pick a lower one (default: driver default)
+    #[arg(long, global = true, value_name = "FPS")]
+    pub camera_fps: Option<f64>,
+
+    /// Pixel format requested from a video device, e.g. MJPG for USB cameras that are slow in YUYV (default: driver default)
+    #[arg(long, global = true, value_name = "FOURCC")]
+    pub fourcc: Option<String>,
+
+    /// Exposure of a video device, in driver units; usually needs --auto-exposure off
+    #[arg(long, global = true, value_name = "VALUE")]
+    pub exposure: Option<f64>,
+
+    /// Gain of a video device, in driver units
+    #[arg(long, global = true, value_name = "VALUE")]
+    pub gain: Option<f64>,
+
+    /// Brightness of a video device, in driver units
+    #[arg(long, global = true, value_name = "VALUE")]
+    pub brightness: Option<f64>,
+
+    /// Automatic exposure of a video device
+    #[arg(long, global = true, value_enum, value_name = "on|off")]
+    pub auto_exposure: Option<Switch>,
+
+    /// MQTT broker host or IP (default: 192.168.1.78)
+    #[arg(long, global = true, value_name = "HOST")]
+    pub broker: Option<String>,
+
+    /// MQTT broker port (default: 1883)
+    #[arg(long, global = true, value_name = "PORT")]
+    pub port: Option<u16>,
+
+    /// Base MQTT topic, counts go to <base>/<device-id>/count with --device-id, else to <base> (default: person_detector)
+    #[arg(long, global = true, alias = "topic-base", value_name = "TOPIC")]
+    pub mqtt_topic: Option<String>,
+
+    /// Name of this detector, used in its topics and appended to the client id
+    #[arg(long, global = true, value_name = "ID")]
+    pub device_id: Option<String>,
+
+    /// MQTT client id, must be unique per detector on a broker (default: person_detector)
+    #[arg(long, global = true, value_name = "ID")]
+    pub client_id: Option<String>,
+
+    /// Published payload, the bare people count or a JSON event (default: plain)
+    #[arg(long, global = true, value_enum, value_name = "FORMAT")]
+    pub payload_format: Option<PayloadFormat>,
+
+    /// MQTT username, for brokers that don't allow anonymous clients
+    #[arg(long, global = true, env = "MQTT_USERNAME", value_name = "USER")]
+    pub mqtt_username: Option<String>,
+
+    /// MQTT password, prefer the environment variable so it doesn't show up in `ps`
+    #[arg(long, global = true, env = "MQTT_PASSWORD", hide_env_values = true, value_name = "PASSWORD")]
+    pub mqtt_password: Option<String>,
+
+    /// Connect to the broker over TLS, usually together with --port 8883
+    #[arg(long, global = true)]
+    pub mqtt_tls: bool,
+
+    /// CA certificate (PEM) the broker certificate is checked against
+    #[arg(long, global = true, value_name = "PATH")]
+    pub mqtt_ca_cert: Option<PathBuf>,
+
+    /// Client certificate (PEM) for brokers requiring mutual TLS
+    #[arg(long, global = true, value_name = "PATH", requires = "mqtt_client_key")]
+    pub mqtt_client_cert: Option<PathBuf>,
+
+    /// Private key (PEM) of the client certificate
+    #[arg(long, global = true, value_name = "PATH", requires = "mqtt_client_cert")]
+    pub mqtt_client_key: Option<PathBuf>,
+
+    /// Skip TLS host name verification, only for self-signed local brokers
+    #[arg(long, global = true)]
+    pub mqtt_insecure: bool,
+
+    /// MQTT QoS level: 0 at most once, 1 at least once, 2 exactly once (default: 1)
+    #[arg(long, global = true, value_name = "0|1|2", value_parser = clap::value_parser!(u8).range(0..=2))]
+    pub mqtt_qos: Option<u8>,
+
+    /// Publish counts retained so new subscribers get the latest one immediately
+    #[arg(long, global = true)]
+    pub mqtt_retain: bool,
+
+    /// Run the detector on every Nth frame only, the frames in between reuse its boxes (default: 1)
+    #[arg(long, global = true, value_name = "N", visible_alias = "detect-interval")]
+    pub process_every_n: Option<u64>,
+
+    /// Re-publish an unchanged count after this many seconds, 0 disables the heartbeat (default: 30)
+    #[arg(long, global = true, value_name = "SECONDS")]
+    pub publish_interval: Option<u64>,
+
+    /// Publish heartbeats retained so late subscribers get the last count right away
+    #[arg(long, global = true)]
+    pub heartbeat_retain: bool,
+
+    /// Publish on every frame instead of only when the count changes
+    #[arg(long, global = true)]
+    pub publish_every_frame: bool,
+
+    /// Publish an alert once at least this many people are present
+    #[arg(long, global = true, value_name = "COUNT")]
+    pub alert_threshold: Option<usize>,
+
+    /// Topic for alerts (default: <topic>/alert)
+    #[arg(long, global = true, value_name = "TOPIC")]
+    pub alert_topic: Option<String>,
+
+    /// Publish each tracked person's box to <topic>/person/<track_id>
+    #[arg(long, global = true)]
+    pub publish_individual_detections: bool,
+
+    /// Announce the count and occupancy to Home Assistant via MQTT discovery
+    #[arg(long, global = true)]
+    pub ha_discovery: bool,
+
+    /// Remove the Home Assistant entities again on a clean shutdown
+    #[arg(long, global = true, requires = "ha_discovery")]
+    pub ha_discovery_clean: bool,
+
+    /// Serve Prometheus metrics on this port, enabling the endpoint (default: 9090)
+    #[arg(long, global = true, value_name = "PORT")]
+    pub metrics_port: Option<u16>,
+
+    /// Serve /state, /count and /health on this port, enabling the HTTP server (default: 8080)
+    #[arg(long, global = true, value_name = "PORT")]
+    pub http_port: Option<u16>,
+
+    /// Run without the preview window, stop with Ctrl-C
+    #[arg(long, global = true)]
+    pub headless: bool,
+
+    /// Exit with a list of working video devices instead of using the first one when the configured device doesn't open
+    #[arg(long, global = true)]
+    pub no_auto_camera: bool,
+
+    /// Restart a video file source from the beginning instead of exiting at its end
+    #[arg(long, global = true, visible_alias = "loop")]
+    pub loop_video: bool,
+
+    /// Play a video file source at its native frame rate instead of as fast as possible
+    #[arg(long, global = true)]
+    pub realtime: bool,
+
+    /// Rotate frames clockwise by this many degrees right after capture (default: 0)
+    #[arg(long, global = true, value_name = "0|90|180|270", value_parser = parse_rotation)]
+    pub rotate: Option<u16>,
+
+    /// Mirror frames right after capture, applied after --rotate
+    #[arg(long, global = true, value_enum, value_name = "DIRECTION")]
+    pub flip: Option<Flip>,
+
+    /// Accumulate where people were and save it as a PNG heatmap to this directory
+    #[arg(long, global = true, value_name = "DIR")]
+    pub heatmap_dir: Option<PathBuf>,
+
+    /// Record the annotated video into this one file, e.g. output.avi
+    #[arg(long, global = true, value_name = "PATH")]
+    pub record: Option<PathBuf>,
+
+    /// With --record, leave out frames nobody is in
+    #[arg(long, global = true)]
+    pub record_skip_empty: bool,
+
+    /// Save a JPEG of the annotated frame to this directory while people are in view
+    #[arg(long, global = true, value_name = "DIR")]
+    pub snapshot_dir: Option<PathBuf>,
+
+    /// Only save a snapshot when someone enters an empty scene
+    #[arg(long, global = true)]
+    pub snapshot_on_change: bool,
+
+    /// Save snapshots as captured, without boxes or overlay
+    #[arg(long, global = true)]
+    pub snapshot_raw: bool,
+
+    /// Log every detection pass to this SQLite file, see `export-csv`
+    #[arg(long, global = true, value_name = "PATH")]
+    pub db_path: Option<PathBuf>,
+
+    /// Write counts to this InfluxDB v2 server, e.g. http://localhost:8086
+    #[arg(long, global = true, value_name = "URL")]
+    pub influxdb_url: Option<String>,
+
+    /// InfluxDB API token, prefer the environment variable so it doesn't show up in `ps`
+    #[arg(long, global = true, env = "INFLUXDB_TOKEN", hide_env_values = true, value_name = "TOKEN")]
+    pub influxdb_token: Option<String>,
+
+    /// Access key for uploading snapshots to S3, see `[s3_upload]`
+    #[arg(long, global = true, env = "AWS_ACCESS_KEY_ID", value_name = "KEY")]
+    pub s3_access_key_id: Option<String>,
+
+    /// Secret key for uploading snapshots to S3, prefer the environment variable
+    #[arg(long, global = true, env = "AWS_SECRET_ACCESS_KEY", hide_env_values = true, value_name = "SECRET")]
+    pub s3_secret_access_key: Option<String>,
+
+    /// Append every detection event as a JSON line to this file, rotated by size
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// Only log warnings and errors, without the people count of every frame
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Also log detection time and box coordinates of every frame
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+
+    /// Log without colors, also set by a non-empty NO_COLOR. Without a terminal logs are never colored.
+    #[arg(long, global = true)]
+    pub no_color: bool,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+
+    /// Deprecated positional form: camera_index_or_url frame_width frame_height broker_ip broker_port mqtt_topic client_id
+    #[arg(hide = true)]
+    pub legacy: Vec<String>,
+}
+
+impl Cli {
+    // Log filter for `--quiet` / `--verbose`, RUST_LOG still takes precedence
+    pub fn log_filter(&self) -> &'static str {
+        if self.quiet {
+            "warn"
+        } else if self.verbose {
+            "info,raspberrypi_people_detection=debug"
+        } else {
+            "info"
+        }
+    }
+
+    pub fn color_disabled(&self) -> bool {
+        self.no_color || std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub camera: CameraConfig,
+    pub broker: BrokerConfig,
+    pub detector: DetectorConfig,
+    pub hog: HogParams,
+    pub preprocessing: PreprocessingConfig,
+    pub yolo: YoloParams,
+    pub dnn: DnnParams,
+    pub mog2: Mog2Params,
+    pub display: DisplayConfig,
+    pub tracker: TrackerConfig,
+    pub metrics: MetricsConfig,
+    pub http: HttpConfig,
+    pub websocket: WebSocketConfig,
+    pub snapshot_publish: SnapshotPublishConfig,
+    pub snapshots: SnapshotsConfig,
+    pub s3_upload: S3UploadConfig,
+    pub recording: RecordingConfig,
+    pub home_assistant: HomeAssistantConfig,
+    pub motion: MotionConfig,
+    pub transitions: TransitionsConfig,
+    pub line_crossing: LineCrossingConfig,
+    pub heatmap: HeatmapConfig,
+    pub alert: AlertConfig,
+    pub commands: CommandsConfig,
+    pub database: DatabaseConfig,
+    pub influxdb: InfluxConfig,
+    pub log_file: LogFileConfig,
+    #[serde(default)]
+    pub zones: Vec<Zone>,
+    // Several cameras in one process instead of `[camera]`, see `multi_camera`
+    #[serde(default)]
+    pub cameras: Vec<CameraEntry>,
+    // Only set from the command line, see `dry-run`
+    #[serde(skip)]
+    pub dry_run: Option<DryRun>,
+    // Only set from the command line, frames to time, see `benchmark`
+    #[serde(skip)]
+    pub benchmark: Option<u64>,
+    // Only set from the command line, see `export-csv`
+    #[serde(skip)]
+    pub export_csv: Option<ExportCsv>,
+    // Only set from the command line, see `print-config`
+    #[serde(skip)]
+    pub print_config: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct DryRun {
+    pub input: PathBuf,
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportCsv {
+    pub db_path: PathBuf,
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogFileConfig {
+    pub enabled: bool,
+    pub path: PathBuf,
+    pub max_size_mb: u64,
+    pub max_files: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub enabled: bool,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InfluxConfig {
+    // Base URL of the server, unset disables the output
+    pub url: Option<String>,
+    pub org: String,
+    pub bucket: String,
+    pub token: Option<String>,
+    pub measurement: String,
+    pub flush_interval_ms: u64,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CliCommand {
+    /// Run the detector, the same as giving no subcommand
+    Run,
+    /// Run detection over a video file and print a summary, without connecting to MQTT
+    DryRun {
+        #[arg(value_name = "INPUT_VIDEO")]
+        input: PathBuf,
+        /// Write the annotated video to this file (MJPG, e.g. out.avi)
+        #[arg(long, value_name = "OUTPUT_VIDEO")]
+        output: Option<PathBuf>,
+    },
+    /// Time the detector on this many frames from the camera or file, print the results and exit
+    #[command(long_flag = "benchmark")]
+    Benchmark {
+        #[arg(value_name = "FRAMES", value_parser = clap::value_parser!(u64).range(1..))]
+        frames: u64,
+    },
+    /// Print the effective configuration as TOML and exit
+    PrintConfig,
+    /// Write the events stored by `--db-path` to a CSV file and exit
+    ExportCsv {
+        // `--db-path` already names the flag logging the events
+        #[arg(id = "export_db_path", value_name = "DB_PATH")]
+        db_path: PathBuf,
+        #[arg(value_name = "OUTPUT_CSV")]
+        output: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraConfig {
+    #[serde(alias = "index")]
+    pub source: CameraSource,
+    pub frame_width: f64,
+    pub frame_height: f64,
+    // Requested capture rate, only applies to video devices
+    pub fps: Option<f64>,
+    // Pixel format of video devices, e.g. "MJPG"
+    pub fourcc: Option<String>,
+    // Image controls of video devices, unset leaves the driver's value
+    pub exposure: Option<f64>,
+    pub gain: Option<f64>,
+    pub brightness: Option<f64>,
+    pub auto_exposure: Option<Switch>,
+    pub open_attempts: u32,
+    pub open_retry_ms: u64,
+    // Fall back to the first working video device, see `Camera::open_or_discover`
+    pub auto_discover: bool,
+    pub reconnect_attempts: u32,
+    pub reconnect_backoff_ms: u64,
+    pub max_failed_reads: u32,
+    pub failed_read_timeout_ms: u64,
+    pub frame_queue_capacity: usize,
+    pub loop_video: bool,
+    pub realtime: bool,
+    // Clockwise, in degrees, see `orientation::Orientation`
+    pub rotate: u16,
+    pub flip: Option<Flip>,
+}
+
+impl CameraConfig {
+    pub fn controls(&self) -> CameraControls {
+        CameraControls {
+            exposure: self.exposure,
+            gain: self.gain,
+            brightness: self.brightness,
+            auto_exposure: self.auto_exposure,
+        }
+    }
+
+    // Values set in `controls` replace the configured ones
+    pub fn update_controls(&mut self, controls: &CameraControls) {
+        self.exposure = controls.exposure.or(self.exposure);
+        self.gain = controls.gain.or(self.gain);
+        self.brightness = controls.brightness.or(self.brightness);
+        self.auto_exposure = controls.auto_exposure.or(self.auto_exposure);
+    }
+}
+
+// Exposure and friends, from `[camera]` or the `set_camera` command. Units
+// and ranges are up to the driver, see `v4l2-ctl --list-ctrls`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraControls {
+    pub exposure: Option<f64>,
+    pub gain: Option<f64>,
+    pub brightness: Option<f64>,
+    pub auto_exposure: Option<Switch>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Switch {
+    On,
+    Off,
+}
+
+// One of several cameras run by the same process, everything not set here
+// comes from `[camera]`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraEntry {
+    // Topic level of this camera, counts go to <topic>/<device_id>/count
+    #[serde(alias = "mqtt_topic_suffix")]
+    pub device_id: String,
+    pub source: CameraSource,
+    pub frame_width: Option<f64>,
+    pub frame_height: Option<f64>,
+    // Zones of this camera's frame, the top-level `zones` don't apply here
+    #[serde(default)]
+    pub zones: Vec<Zone>,
+}
+
+impl CameraEntry {
+    pub fn camera_config(&self, defaults: &CameraConfig) -> CameraConfig {
+        CameraConfig {
+            source: self.source.clone(),
+            frame_width: self.frame_width.unwrap_or(defaults.frame_width),
+            frame_height: self.frame_height.unwrap_or(defaults.frame_height),
+            ..defaults.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BrokerConfig {
+    pub ip: String,
+    pub port: u16,
+    #[serde(alias = "topic_base")]
+    pub topic: String,
+    pub client_id: String,
+    pub device_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub payload_format: PayloadFormat,
+    pub qos: u8,
+    pub retain: bool,
+    pub publish_queue_capacity: usize,
+    pub max_packet_size: usize,
+    pub dead_letter_capacity: usize,
+    // Payload and topic bytes, keeps a few snapshots from filling the memory
+    pub dead_letter_max_bytes: usize,
+    pub publish_interval_secs: u64,
+    pub heartbeat_retain: bool,
+    pub publish_every_frame: bool,
+    pub publish_individual_detections: bool,
+    pub availability_subtopic: String,
+    pub availability_format: PayloadFormat,
+    pub lwt_topic: Option<String>,
+    pub lwt_payload: Option<String>,
+    pub lwt_qos: u8,
+    pub tls: bool,
+    pub tls_insecure: bool,
+    #[serde(alias = "tls_ca_cert")]
+    pub ca_cert_path: Option<PathBuf>,
+    #[serde(alias = "tls_client_cert")]
+    pub client_cert_path: Option<PathBuf>,
+    #[serde(alias = "tls_client_key")]
+    pub client_key_path: Option<PathBuf>,
+}
+
+impl BrokerConfig {
+    // Setting any certificate implies TLS, `tls = true` alone uses just the CA
+    pub fn tls_enabled(&self) -> bool {
+        self.tls
+            || self.ca_cert_path.is_some()
+            || self.client_cert_path.is_some()
+            || self.client_key_path.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Flip {
+    Horizontal,
+    Vertical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+    Plain,
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DetectorConfig {
+    #[serde(rename = "type")]
+    pub detector_type: DetectorType,
+    pub process_every_n: u64,
+    pub debounce_frames: u32,
+    pub roi: Option<Roi>,
+    // Plausible person boxes in frame pixels, see `detector::SizeFilter`
+    pub min_person_bbox: Option<(i32, i32)>,
+    pub max_person_bbox: Option<(i32, i32)>,
+    // Width divided by height
+    pub aspect_ratio_min: f64,
+    pub aspect_ratio_max: f64,
+}
+
+// Part of the frame the detector looks at, in frame pixels
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Roi {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectorType {
+    Hog,
+    Yolo,
+    /// MobileNet-SSD Caffe model
+    Dnn,
+    /// Moving foreground blobs from MOG2 background subtraction, for static cameras
+    Mog2,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HogParams {
+    pub hit_threshold: f64,
+    pub win_stride: (i32, i32),
+    pub padding: (i32, i32),
+    pub scale: f64,
+    pub final_threshold: f64,
+    pub use_meanshift_grouping: bool,
+    pub nms_threshold: f64,
+    pub detect_width: Option<i32>,
+    pub detect_height: Option<i32>,
+    pub detect_scale: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PreprocessingConfig {
+    pub steps: Vec<PreprocessStep>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct YoloParams {
+    pub model_path: String,
+    pub input_size: i32,
+    pub confidence_threshold: f32,
+    pub nms_threshold: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnnParams {
+    pub prototxt_path: String,
+    pub model_path: String,
+    pub confidence_threshold: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mog2Params {
+    pub history: i32,
+    pub var_threshold: f64,
+    pub detect_shadows: bool,
+    // Smallest foreground blob in pixels that counts
+    pub min_area: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertConfig {
+    pub threshold: Option<usize>,
+    pub rearm_below: Option<usize>,
+    pub topic: Option<String>,
+    pub payload: AlertPayload,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertPayload {
+    // The literal "ALERT"
+    Alert,
+    // The people count that raised the alert
+    Count,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransitionsConfig {
+    pub enabled: bool,
+    pub debounce_passes: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LineCrossingConfig {
+    pub enabled: bool,
+    pub orientation: LineOrientation,
+    // Pixel row of a horizontal line or column of a vertical one
+    pub position: i32,
+    // Any line through two frame points instead of `orientation` and `position`
+    pub from: Option<(i32, i32)>,
+    pub to: Option<(i32, i32)>,
+    // Pixels a centroid must be past the line on either side
+    pub hysteresis: f32,
+    // Count right to left as "in" instead
+    pub invert: bool,
+}
+
+// Reaches past the edge of any frame, drawing clips it
+const LINE_EXTENT: i32 = 100_000;
+
+impl LineCrossingConfig {
+    // Two points on the line. A horizontal line points right and a vertical
+    // one up, so crossing downwards or to the right is left to right.
+    pub fn line(&self) -> ((i32, i32), (i32, i32)) {
+        match (self.from, self.to, self.orientation) {
+            (Some(from), Some(to), _) => (from, to),
+            (_, _, LineOrientation::Horizontal) => ((0, self.position), (LINE_EXTENT, self.position)),
+            (_, _, LineOrientation::Vertical) => ((self.position, LINE_EXTENT), (self.position, 0)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineOrientation {
+    Horizontal,
+    Vertical,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrackerConfig {
+    pub max_distance: f32,
+    pub max_missed_frames: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebSocketConfig {
+    pub enabled: bool,
+    pub address: SocketAddr,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotsConfig {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    pub min_interval_secs: u64,
+    pub on_change: bool,
+    pub quality: i32,
+    // False keeps the frame as captured, without boxes or overlay
+    pub annotated: bool,
+    // Write a .json file with the count and boxes next to every JPEG
+    pub metadata: bool,
+}
+
+// Copies every saved snapshot to an S3-compatible bucket, needs the
+// `s3-upload` feature
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct S3UploadConfig {
+    pub enabled: bool,
+    // http:// or https://, objects are addressed as <endpoint>/<bucket>/<key>
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    // Prepended to the file name to form the object key, e.g. "front_door/"
+    pub prefix: String,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeatmapConfig {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    pub save_interval_secs: u64,
+    // Blend it into the preview
+    pub show: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotPublishConfig {
+    pub enabled: bool,
+    pub quality: i32,
+    pub min_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordingConfig {
+    pub enabled: bool,
+    pub record_threshold: usize,
+    pub record_cooldown_secs: u64,
+    pub output_dir: PathBuf,
+    // Everything goes into this one file instead of one file per visit
+    pub path: Option<PathBuf>,
+    // With `path`, frames nobody is in aren't written
+    pub skip_empty: bool,
+    pub fourcc: String,
+    pub fps: f64,
+}
+
+impl RecordingConfig {
+    // Where the recordings go, created at startup
+    pub fn dir(&self) -> &Path {
+        match &self.path {
+            Some(path) => path.parent().unwrap_or(Path::new("")),
+            None => &self.output_dir,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandsConfig {
+    pub enabled: bool,
+    pub snapshot_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MotionConfig {
+    pub enabled: bool,
+    pub threshold_pct: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HomeAssistantConfig {
+    pub discovery: bool,
+    pub discovery_clean: bool,
+    pub discovery_prefix: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DisplayConfig {
+    pub headless: bool,
+    pub auto_headless: bool,
+    pub show_overlay: bool,
+    // Corner of the count, the time goes in the other corner on the same side
+    pub overlay_position: OverlayPosition,
+    pub overlay_font_scale: f64,
+    pub zone_color: (u8, u8, u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlayPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io { path: PathBuf, source: std::io::Error },
+    Parse { origin: String, source: toml::de::Error },
+    Args(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "unable to read {}: {}", path.display(), source)
+            }
+            ConfigError::Parse { origin, source } => write!(f, "invalid config in {}: {}", origin, source),
+            ConfigError::Args(message) => write!(f, "invalid arguments: {}", message),
+            ConfigError::Invalid(message) => write!(f, "invalid config: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            ConfigError::Parse { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from the command line, see [`Cli`].
+    pub fn from_cli(cli: Cli) -> Result<Config, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        // Dotted keys of every value set on the command line, for the startup log
+        let mut overridden = Vec::new();
+
+        if cli.loop_video {
+            config.camera.loop_video = true;
+            overridden.push("camera.loop_video");
+        }
+        if cli.realtime {
+            config.camera.realtime = true;
+            overridden.push("camera.realtime");
+        }
+        if cli.no_auto_camera {
+            config.camera.auto_discover = false;
+            overridden.push("camera.auto_discover");
+        }
+        if let Some(fps) = cli.camera_fps {
+            config.camera.fps = Some(fps);
+            overridden.push("camera.fps");
+        }
+        if let Some(fourcc) = cli.fourcc {
+            config.camera.fourcc = Some(fourcc);
+            overridden.push("camera.fourcc");
+        }
+        if let Some(exposure) = cli.exposure {
+            config.camera.exposure = Some(exposure);
+            overridden.push("camera.exposure");
+        }
+        if let Some(gain) = cli.gain {
+            config.camera.gain = Some(gain);
+            overridden.push("camera.gain");
+        }
+        if let Some(brightness) = cli.brightness {
+            config.camera.brightness = Some(brightness);
+            overridden.push("camera.brightness");
+        }
+        if let Some(auto_exposure) = cli.auto_exposure {
+            config.camera.auto_exposure = Some(auto_exposure);
+            overridden.push("camera.auto_exposure");
+        }
+        if let Some(rotate) = cli.rotate {
+            config.camera.rotate = rotate;
+            overridden.push("camera.rotate");
+        }
+        if let Some(flip) = cli.flip {
+            config.camera.flip = Some(flip);
+            overridden.push("camera.flip");
+        }
+        match cli.command {
+            None | Some(CliCommand::Run) => {}
+            Some(CliCommand::DryRun { input, output }) => config.dry_run = Some(DryRun { input, output }),
+            Some(CliCommand::Benchmark { frames }) => config.benchmark = Some(frames),
+            Some(CliCommand::PrintConfig) => config.print_config = true,
+            Some(CliCommand::ExportCsv { db_path, output }) => config.export_csv = Some(ExportCsv { db_path, output }),
+        }
+
+        if !cli.legacy.is_empty() {
+            log::warn!(
+                "Positional arguments are deprecated and will be removed, use the named flags (see --help) instead"
+            );
+            config.apply_positional(&cli.legacy, &mut overridden)?;
+        }
+
+        if let Some(source) = cli.camera_source {
+            config.camera.source = source;
+            overridden.push("camera.source");
+        }
+        if let Some(gstreamer) = cli.gst_pipeline {
+            config.camera.source = CameraSource::Pipeline { gstreamer };
+            overridden.push("camera.source");
+        }
+        if let Some(detector) = cli.detector {
+            config.detector.detector_type = detector;
+            overridden.push("detector.type");
+        }
+        if let Some(hit_threshold) = cli.hog_hit_threshold {
+            config.hog.hit_threshold = hit_threshold;
+            overridden.push("hog.hit_threshold");
+        }
+        if let Some(win_stride) = cli.hog_win_stride {
+            config.hog.win_stride = win_stride;
+            overridden.push("hog.win_stride");
+        }
+        if let Some(padding) = cli.hog_padding {
+            config.hog.padding = padding;
+            overridden.push("hog.padding");
+        }
+        if let Some(scale) = cli.hog_scale {
+            config.hog.scale = scale;
+            overridden.push("hog.scale");
+        }
+        if let Some(group_threshold) = cli.hog_group_threshold {
+            config.hog.final_threshold = group_threshold;
+            overridden.push("hog.final_threshold");
+        }
+        if let Some(nms_threshold) = cli.hog_nms_threshold {
+            config.hog.nms_threshold = nms_threshold;
+            overridden.push("hog.nms_threshold");
+        }
+        if let Some(width) = cli.detect_width {
+            config.hog.detect_width = Some(width);
+            overridden.push("hog.detect_width");
+        }
+        if let Some(height) = cli.detect_height {
+            config.hog.detect_height = Some(height);
+            overridden.push("hog.detect_height");
+        }
+        if let Some(factor) = cli.detect_scale {
+            config.hog.detect_scale = Some(factor);
+            overridden.push("hog.detect_scale");
+        }
+        if let Some(width) = cli.width {
+            config.camera.frame_width = width;
+            overridden.push("camera.frame_width");
+        }
+        if let Some(height) = cli.height {
+            config.camera.frame_height = height;
+            overridden.push("camera.frame_height");
+        }
+        if let Some(broker) = cli.broker {
+            config.broker.ip = broker;
+            overridden.push("broker.ip");
+        }
+        if let Some(port) = cli.port {
+            config.broker.port = port;
+            overridden.push("broker.port");
+        }
+        if let Some(topic) = cli.mqtt_topic {
+            config.broker.topic = topic;
+            overridden.push("broker.topic");
+        }
+        if let Some(client_id) = cli.client_id {
+            config.broker.client_id = client_id;
+            overridden.push("broker.client_id");
+        }
+        if let Some(device_id) = cli.device_id {
+            config.broker.device_id = Some(device_id);
+            overridden.push("broker.device_id");
+        }
+        if let Some(format) = cli.payload_format {
+            config.broker.payload_format = format;
+            overridden.push("broker.payload_format");
+        }
+        if let Some(username) = cli.mqtt_username {
+            config.broker.username = Some(username);
+            overridden.push("broker.username");
+        }
+        if let Some(password) = cli.mqtt_password {
+            config.broker.password = Some(password);
+            overridden.push("broker.password");
+        }
+        if cli.mqtt_tls {
+            config.broker.tls = true;
+            overridden.push("broker.tls");
+        }
+        if let Some(path) = cli.mqtt_ca_cert {
+            config.broker.ca_cert_path = Some(path);
+            overridden.push("broker.ca_cert_path");
+        }
+        if let Some(path) = cli.mqtt_client_cert {
+            config.broker.client_cert_path = Some(path);
+            overridden.push("broker.client_cert_path");
+        }
+        if let Some(path) = cli.mqtt_client_key {
+            config.broker.client_key_path = Some(path);
+            overridden.push("broker.client_key_path");
+        }
+        if cli.mqtt_insecure {
+            config.broker.tls_insecure = true;
+            overridden.push("broker.tls_insecure");
+        }
+        if let Some(qos) = cli.mqtt_qos {
+            config.broker.qos = qos;
+            overridden.push("broker.qos");
+        }
+        if let Some(n) = cli.process_every_n {
+            config.detector.process_every_n = n;
+            overridden.push("detector.process_every_n");
+        }
+        if cli.mqtt_retain {
+            config.broker.retain = true;
+            overridden.push("broker.retain");
+        }
+        if let Some(interval) = cli.publish_interval {
+            config.broker.publish_interval_secs = interval;
+            overridden.push("broker.publish_interval_secs");
+        }
+        if cli.heartbeat_retain {
+            config.broker.heartbeat_retain = true;
+            overridden.push("broker.heartbeat_retain");
+        }
+        if cli.ha_discovery {
+            config.home_assistant.discovery = true;
+            overridden.push("home_assistant.discovery");
+        }
+        if cli.ha_discovery_clean {
+            config.home_assistant.discovery_clean = true;
+            overridden.push("home_assistant.discovery_clean");
+        }
+        if cli.publish_every_frame {
+            config.broker.publish_every_frame = true;
+            overridden.push("broker.publish_every_frame");
+        }
+        if let Some(threshold) = cli.alert_threshold {
+            config.alert.threshold = Some(threshold);
+            overridden.push("alert.threshold");
+        }
+        if let Some(topic) = cli.alert_topic {
+            config.alert.topic = Some(topic);
+            overridden.push("alert.topic");
+        }
+        if cli.publish_individual_detections {
+            config.broker.publish_individual_detections = true;
+            overridden.push("broker.publish_individual_detections");
+        }
+        if let Some(port) = cli.metrics_port {
+            config.metrics.enabled = true;
+            config.metrics.port = port;
+            overridden.push("metrics.enabled");
+            overridden.push("metrics.port");
+        }
+        if let Some(path) = cli.db_path {
+            config.database.enabled = true;
+            config.database.path = path;
+            overridden.push("database.enabled");
+            overridden.push("database.path");
+        }
+        if let Some(url) = cli.influxdb_url {
+            config.influxdb.url = Some(url);
+            overridden.push("influxdb.url");
+        }
+        if let Some(token) = cli.influxdb_token {
+            config.influxdb.token = Some(token);
+            overridden.push("influxdb.token");
+        }
+        if let Some(key) = cli.s3_access_key_id {
+            config.s3_upload.access_key_id = Some(key);
+            overridden.push("s3_upload.access_key_id");
+        }
+        if let Some(secret) = cli.s3_secret_access_key {
+            config.s3_upload.secret_access_key = Some(secret);
+            overridden.push("s3_upload.secret_access_key");
+        }
+        if let Some(dir) = cli.heatmap_dir {
+            config.heatmap.enabled = true;
+            config.heatmap.dir = dir;
+            overridden.push("heatmap.enabled");
+            overridden.push("heatmap.dir");
+        }
+        if let Some(path) = cli.record {
+            config.recording.enabled = true;
+            config.recording.path = Some(path);
+            overridden.push("recording.enabled");
+            overridden.push("recording.path");
+        }
+        if cli.record_skip_empty {
+            config.recording.skip_empty = true;
+            overridden.push("recording.skip_empty");
+        }
+        if let Some(dir) = cli.snapshot_dir {
+            config.snapshots.enabled = true;
+            config.snapshots.dir = dir;
+            overridden.push("snapshots.enabled");
+            overridden.push("snapshots.dir");
+        }
+        if cli.snapshot_on_change {
+            config.snapshots.on_change = true;
+            overridden.push("snapshots.on_change");
+        }
+        if cli.snapshot_raw {
+            config.snapshots.annotated = false;
+            overridden.push("snapshots.annotated");
+        }
+        if let Some(path) = cli.log_file {
+            config.log_file.enabled = true;
+            config.log_file.path = path;
+            overridden.push("log_file.enabled");
+            overridden.push("log_file.path");
+        }
+        if let Some(port) = cli.http_port {
+            config.http.enabled = true;
+            config.http.port = port;
+            overridden.push("http.enabled");
+            overridden.push("http.port");
+        }
+        if cli.headless {
+            config.display.headless = true;
+            overridden.push("display.headless");
+        }
+
+        if !config.display.headless && config.display.auto_headless && !display_available() {
+            log::info!("No DISPLAY or WAYLAND_DISPLAY set, running headless");
+            config.display.headless = true;
+        }
+
+        config.validate()?;
+        config.log_sources(cli.config.as_deref(), &overridden);
+        Ok(config)
+    }
+
+    /// Reads a TOML file and merges it over the built-in defaults. Not
+    /// validated yet, the command line may still override what is wrong.
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        Config::from_table(read_table(path)?, &path.display().to_string())
+    }
+
+    fn from_table(table: toml::Table, origin: &str) -> Result<Config, ConfigError> {
+        let mut merged = parse_table(DEFAULT_CONFIG, "built-in defaults")?;
+        merge_tables(&mut merged, table);
+
+        let mut config: Config = toml::Value::Table(merged)
+            .try_into()
+            .map_err(|source| ConfigError::Parse {
+                origin: origin.to_string(),
+                source,
+            })?;
+        config.single_camera_entry();
+        Ok(config)
+    }
+
+    // A single `[[cameras]]` entry runs like `[camera]`, with all the features
+    // that are only available for one camera and the same count topic
+    fn single_camera_entry(&mut self) {
+        if self.cameras.len() != 1 {
+            return;
+        }
+        let entry = self.cameras.remove(0);
+        self.camera = entry.camera_config(&self.camera);
+        if !entry.zones.is_empty() {
+            self.zones = entry.zones;
+        }
+        self.broker.device_id = Some(entry.device_id);
+    }
+
+    // Effective config for `print-config`, secrets masked like in the log
+    pub fn to_toml(&self) -> Result<String, ConfigError> {
+        let mut effective = toml::Table::try_from(self)
+            .map_err(|e| ConfigError::Invalid(format!("unable to print the config: {}", e)))?;
+        for key in SECRET_KEYS {
+            let (section, name) = key.split_once('.').expect("secret keys are section.key");
+            if let Some(value) = effective
+                .get_mut(section)
+                .and_then(|section| section.as_table_mut())
+                .and_then(|section| section.get_mut(name))
+            {
+                *value = toml::Value::String("***".to_string());
+            }
+        }
+        toml::to_string_pretty(&effective)
+            .map_err(|e| ConfigError::Invalid(format!("unable to print the config: {}", e)))
+    }
+
+    fn validate_s3_upload(&self) -> Result<(), ConfigError> {
+        let s3 = &self.s3_upload;
+        if !cfg!(feature = "s3-upload") {
+            return Err(ConfigError::Invalid(
+                "s3_upload.enabled needs a build with `--features s3-upload`".to_string(),
+            ));
+        }
+        if !self.snapshots.enabled {
+            return Err(ConfigError::Invalid(
+                "s3_upload uploads saved snapshots, it needs snapshots.enabled".to_string(),
+            ));
+        }
+        let endpoint_valid = (s3.endpoint.starts_with("http://") || s3.endpoint.starts_with("https://"))
+            && s3.endpoint.parse::<hyper::Uri>().is_ok_and(|uri| uri.host().is_some());
+        if !endpoint_valid {
+            return Err(ConfigError::Invalid(format!(
+                "s3_upload.endpoint must be an http:// or https:// URL, got {:?}",
+                s3.endpoint
+            )));
+        }
+        if s3.bucket.is_empty() {
+            return Err(ConfigError::Invalid("s3_upload.bucket must not be empty".to_string()));
+        }
+        if s3.region.is_empty() {
+            return Err(ConfigError::Invalid("s3_upload.region must not be empty".to_string()));
+        }
+        if s3.access_key_id.is_none() || s3.secret_access_key.is_none() {
+            return Err(ConfigError::Invalid(
+                "s3_upload needs access_key_id and secret_access_key, also AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Logs every effective value together with where it came from
+    fn log_sources(&self, config_path: Option<&Path>, overridden: &[&str]) {
+        if !log::log_enabled!(log::Level::Debug) {
+            return;
+        }
+        let file = config_path.and_then(|path| read_table(path).ok());
+        let Ok(toml::Value::Table(effective)) = toml::Value::try_from(self) else {
+            return;
+        };
+
+        let mut values = Vec::new();
+        flatten_table(&effective, "", &mut values);
+        for (key, value) in values {
+            let source = if overridden.contains(&key.as_str()) {
+                "command line"
+            } else if file.as_ref().is_some_and(|table| lookup(table, &key).is_some()) {
+                "config file"
+            } else {
+                "default"
+            };
+            if SECRET_KEYS.contains(&key.as_str()) {
+                log::debug!("config {} = *** ({})", key, source);
+            } else {
+                log::debug!("config {} = {} ({})", key, value, source);
+            }
+        }
+    }
+
+    // Same order as the original positional parsing
+    fn apply_positional(&mut self, args: &[String], overridden: &mut Vec<&str>) -> Result<(), ConfigError> {
+        if args.len() > 7 {
+            return Err(ConfigError::Args(format!("unexpected argument '{}'", args[7])));
+        }
+        if let Some(arg) = args.first() {
+            // An index for a local device, anything else is opened as a stream URL
+            let Ok(source) = arg.parse::<CameraSource>();
+            self.camera.source = source;
+            overridden.push("camera.source");
+        }
+        if let Some(arg) = args.get(1) {
+            self.camera.frame_width = parse_positional(arg, "frame_width")?;
+            overridden.push("camera.frame_width");
+        }
+        if let Some(arg) = args.get(2) {
+            self.camera.frame_height = parse_positional(arg, "frame_height")?;
+            overridden.push("camera.frame_height");
+        }
+        if let Some(arg) = args.get(3) {
+            self.broker.ip = arg.clone();
+            overridden.push("broker.ip");
+        }
+        if let Some(arg) = args.get(4) {
+            self.broker.port = parse_positional(arg, "broker_port")?;
+            overridden.push("broker.port");
+        }
+        if let Some(arg) = args.get(5) {
+            self.broker.topic = arg.clone();
+            overridden.push("broker.topic");
+        }
+        if let Some(arg) = args.get(6) {
+            self.broker.client_id = arg.clone();
+            overridden.push("broker.client_id");
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.camera.source == CameraSource::Url(String::new()) {
+            return Err(ConfigError::Invalid("camera.source must not be empty".to_string()));
+        }
+        if let CameraSource::Pipeline { gstreamer } = &self.camera.source {
+            if gstreamer.trim().is_empty() {
+                return Err(ConfigError::Invalid("camera.source.gstreamer must not be empty".to_string()));
+            }
+        }
+        if self.camera.frame_width <= 0.0 || self.camera.frame_height <= 0.0 {
+            return Err(ConfigError::Invalid(format!(
+                "camera resolution must be positive, got {}x{}",
+                self.camera.frame_width, self.camera.frame_height
+            )));
+        }
+        if let Some(fps) = self.camera.fps.filter(|fps| *fps <= 0.0) {
+            return Err(ConfigError::Invalid(format!("camera.fps must be positive, got {}", fps)));
+        }
+        if let Some(fourcc) = &self.camera.fourcc {
+            if fourcc.chars().count() != 4 || !fourcc.is_ascii() {
+                return Err(ConfigError::Invalid(format!(
+                    "camera.fourcc must be four characters like MJPG or YUYV, got '{}'",
+                    fourcc
+                )));
+            }
+        }
+        if ![0, 90, 180, 270].contains(&self.camera.rotate) {
+            return Err(ConfigError::Invalid(format!(
+                "camera.rotate must be 0, 90, 180 or 270, got {}",
+                self.camera.rotate
+            )));
+        }
+        if self.camera.frame_queue_capacity == 0 {
+            return Err(ConfigError::Invalid("camera.frame_queue_capacity must be at least 1".to_string()));
+        }
+        if self.broker.ip.trim().is_empty() {
+            return Err(ConfigError::Invalid("broker.ip must not be empty".to_string()));
+        }
+        if self.broker.port == 0 {
+            return Err(ConfigError::Invalid("broker.port must not be 0".to_string()));
+        }
+        if self.broker.topic.is_empty() || self.broker.topic.contains(['+', '#']) {
+            return Err(ConfigError::Invalid(format!(
+                "broker.topic must be a non-empty topic without wildcards, got '{}'",
+                self.broker.topic
+            )));
+        }
+        if self.broker.client_id.is_empty() {
+            return Err(ConfigError::Invalid("broker.client_id must not be empty".to_string()));
+        }
+        let prefix = &self.home_assistant.discovery_prefix;
+        if prefix.is_empty() || prefix.contains(['+', '#']) {
+            return Err(ConfigError::Invalid(format!(
+                "home_assistant.discovery_prefix must be a topic without wildcards, got '{}'",
+                prefix
+            )));
+        }
+        if let Some(device_id) = &self.broker.device_id {
+            if device_id.is_empty() || device_id.contains(['/', '+', '#']) {
+                return Err(ConfigError::Invalid(format!(
+                    "broker.device_id must be a single topic level without wildcards, got '{}'",
+                    device_id
+                )));
+            }
+        }
+        if self.broker.availability_subtopic.is_empty()
+            || self.broker.availability_subtopic.contains(['+', '#'])
+        {
+            return Err(ConfigError::Invalid(format!(
+                "broker.availability_subtopic must be a non-empty topic without wildcards, got '{}'",
+                self.broker.availability_subtopic
+            )));
+        }
+        if let Some(threshold) = self.alert.threshold {
+            if threshold == 0 {
+                return Err(ConfigError::Invalid("alert.threshold must be at least 1".to_string()));
+            }
+            if self.alert.rearm_below.is_some_and(|rearm_below| rearm_below > threshold) {
+                return Err(ConfigError::Invalid(format!(
+                    "alert.rearm_below must not be above alert.threshold ({})",
+                    threshold
+                )));
+            }
+        }
+        if let Some(topic) = &self.alert.topic {
+            if topic.is_empty() || topic.contains(['+', '#']) {
+                return Err(ConfigError::Invalid(format!(
+                    "alert.topic must be a non-empty topic without wildcards, got '{}'",
+                    topic
+                )));
+            }
+        }
+        if let Some(topic) = &self.broker.lwt_topic {
+            if topic.is_empty() || topic.contains(['+', '#']) {
+                return Err(ConfigError::Invalid(format!(
+                    "broker.lwt_topic must be a non-empty topic without wildcards, got '{}'",
+                    topic
+                )));
+            }
+        }
+        if self.broker.lwt_qos > 2 {
+            return Err(ConfigError::Invalid(format!(
+                "broker.lwt_qos must be 0, 1 or 2, got {}",
+                self.broker.lwt_qos
+            )));
+        }
+        if self.broker.password.is_some() && self.broker.username.is_none() {
+            return Err(ConfigError::Invalid(
+                "broker.password needs a broker.username".to_string(),
+            ));
+        }
+        if self.broker.qos > 2 {
+            return Err(ConfigError::Invalid(format!(
+                "broker.qos must be 0 (at most once), 1 (at least once) or 2 (exactly once), got {}",
+                self.broker.qos
+            )));
+        }
+        if !(0.0..=100.0).contains(&self.motion.threshold_pct) {
+            return Err(ConfigError::Invalid(format!(
+                "motion.threshold_pct must be between 0 and 100, got {}",
+                self.motion.threshold_pct
+            )));
+        }
+        if !(0..=100).contains(&self.snapshots.quality) {
+            return Err(ConfigError::Invalid(format!(
+                "snapshots.quality must be between 0 and 100, got {}",
+                self.snapshots.quality
+            )));
+        }
+        if !(0..=100).contains(&self.snapshot_publish.quality) {
+            return Err(ConfigError::Invalid(format!(
+                "snapshot_publish.quality must be between 0 and 100, got {}",
+                self.snapshot_publish.quality
+            )));
+        }
+        if self.broker.max_packet_size < 1024 {
+            return Err(ConfigError::Invalid(format!(
+                "broker.max_packet_size must be at least 1024 bytes, got {}",
+                self.broker.max_packet_size
+            )));
+        }
+        if self.http.enabled && self.metrics.enabled && self.http.port == self.metrics.port {
+            return Err(ConfigError::Invalid(format!(
+                "http.port and metrics.port must differ, both are {}",
+                self.http.port
+            )));
+        }
+        if self.detector.process_every_n == 0 {
+            return Err(ConfigError::Invalid(
+                "detector.process_every_n must be at least 1".to_string(),
+            ));
+        }
+        if self.log_file.max_size_mb == 0 {
+            return Err(ConfigError::Invalid("log_file.max_size_mb must be at least 1".to_string()));
+        }
+        if self.camera.open_attempts == 0 {
+            return Err(ConfigError::Invalid("camera.open_attempts must be at least 1".to_string()));
+        }
+        if self.camera.max_failed_reads == 0 {
+            return Err(ConfigError::Invalid(
+                "camera.max_failed_reads must be at least 1".to_string(),
+            ));
+        }
+        if self.detector.debounce_frames == 0 {
+            return Err(ConfigError::Invalid(
+                "detector.debounce_frames must be at least 1".to_string(),
+            ));
+        }
+        for (key, size) in [
+            ("min_person_bbox", self.detector.min_person_bbox),
+            ("max_person_bbox", self.detector.max_person_bbox),
+        ] {
+            if let Some((width, height)) = size.filter(|(width, height)| *width <= 0 || *height <= 0) {
+                return Err(ConfigError::Invalid(format!(
+                    "detector.{} must be positive, got {}x{}",
+                    key, width, height
+                )));
+            }
+        }
+        if let (Some(min), Some(max)) = (self.detector.min_person_bbox, self.detector.max_person_bbox) {
+            if min.0 > max.0 || min.1 > max.1 {
+                return Err(ConfigError::Invalid(format!(
+                    "detector.min_person_bbox {}x{} is larger than max_person_bbox {}x{}",
+                    min.0, min.1, max.0, max.1
+                )));
+            }
+        }
+        if self.detector.aspect_ratio_min <= 0.0 || self.detector.aspect_ratio_min > self.detector.aspect_ratio_max {
+            return Err(ConfigError::Invalid(format!(
+                "detector.aspect_ratio_min must be positive and at most aspect_ratio_max, got {} and {}",
+                self.detector.aspect_ratio_min, self.detector.aspect_ratio_max
+            )));
+        }
+        if self.transitions.debounce_passes == 0 {
+            return Err(ConfigError::Invalid(
+                "transitions.debounce_passes must be at least 1".to_string(),
+            ));
+        }
+        if self.broker.publish_queue_capacity == 0 {
+            return Err(ConfigError::Invalid("broker.publish_queue_capacity must be at least 1".to_string()));
+        }
+        if self.broker.client_cert_path.is_some() != self.broker.client_key_path.is_some() {
+            return Err(ConfigError::Invalid(
+                "broker.client_cert_path and broker.client_key_path must be set together".to_string(),
+            ));
+        }
+        if let Some(width) = self.hog.detect_width.filter(|&width| width <= 0) {
+            return Err(ConfigError::Invalid(format!(
+                "hog.detect_width must be positive, got {}",
+                width
+            )));
+        }
+        if let Some(height) = self.hog.detect_height.filter(|&height| height <= 0) {
+            return Err(ConfigError::Invalid(format!(
+                "hog.detect_height must be positive, got {}",
+                height
+            )));
+        }
+        if let Some(factor) = self.hog.detect_scale {
+            if !(factor > 0.0 && factor <= 1.0) {
+                return Err(ConfigError::Invalid(format!(
+                    "hog.detect_scale must be above 0 and at most 1, got {}",
+                    factor
+                )));
+            }
+            if self.hog.detect_width.is_some() || self.hog.detect_height.is_some() {
+                return Err(ConfigError::Invalid(
+                    "hog.detect_scale can't be combined with hog.detect_width or hog.detect_height".to_string(),
+                ));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.hog.nms_threshold) {
+            return Err(ConfigError::Invalid(format!(
+                "hog.nms_threshold must be between 0 and 1, got {}",
+                self.hog.nms_threshold
+            )));
+        }
+        if self.hog.scale <= 1.0 {
+            return Err(ConfigError::Invalid(format!("hog.scale must be greater than 1, got {}", self.hog.scale)));
+        }
+        if self.hog.win_stride.0 <= 0 || self.hog.win_stride.1 <= 0 {
+            return Err(ConfigError::Invalid(format!(
+                "hog.win_stride must be positive, got {}x{}",
+                self.hog.win_stride.0, self.hog.win_stride.1
+            )));
+        }
+        if self.hog.padding.0 < 0 || self.hog.padding.1 < 0 {
+            return Err(ConfigError::Invalid(format!(
+                "hog.padding must not be negative, got {}x{}",
+                self.hog.padding.0, self.hog.padding.1
+            )));
+        }
+        if self.recording.record_threshold == 0 {
+            return Err(ConfigError::Invalid("recording.record_threshold must be at least 1".to_string()));
+        }
+        if self.recording.fourcc.chars().count() != 4 || !self.recording.fourcc.is_ascii() {
+            return Err(ConfigError::Invalid(format!(
+                "recording.fourcc must be four characters like MJPG or XVID, got '{}'",
+                self.recording.fourcc
+            )));
+        }
+        if self.recording.fps <= 0.0 {
+            return Err(ConfigError::Invalid(format!(
+                "recording.fps must be positive, got {}",
+                self.recording.fps
+            )));
+        }
+        if self.tracker.max_distance <= 0.0 {
+            return Err(ConfigError::Invalid(format!(
+                "tracker.max_distance must be positive, got {}",
+                self.tracker.max_distance
+            )));
+        }
+        if let Some(url) = &self.influxdb.url {
+            // Only plain HTTP, hyper is built without a TLS connector
+            if !url.starts_with("http://") || url.parse::<hyper::Uri>().is_err() {
+                return Err(ConfigError::Invalid(format!(
+                    "influxdb.url must be an http:// URL, got {:?}",
+                    url
+                )));
+            }
+            if self.influxdb.bucket.is_empty() {
+                return Err(ConfigError::Invalid("influxdb.bucket must not be empty".to_string()));
+            }
+            if self.influxdb.measurement.is_empty() {
+                return Err(ConfigError::Invalid("influxdb.measurement must not be empty".to_string()));
+            }
+            if self.influxdb.flush_interval_ms == 0 {
+                return Err(ConfigError::Invalid("influxdb.flush_interval_ms must be at least 1".to_string()));
+            }
+        }
+        if self.s3_upload.enabled {
+            self.validate_s3_upload()?;
+        }
+        if self.heatmap.enabled && self.heatmap.save_interval_secs == 0 {
+            return Err(ConfigError::Invalid("heatmap.save_interval_secs must be at least 1".to_string()));
+        }
+        if self.display.overlay_font_scale <= 0.0 {
+            return Err(ConfigError::Invalid(format!(
+                "display.overlay_font_scale must be positive, got {}",
+                self.display.overlay_font_scale
+            )));
+        }
+        if self.line_crossing.position < 0 {
+            return Err(ConfigError::Invalid(format!(
+                "line_crossing.position must not be negative, got {}",
+                self.line_crossing.position
+            )));
+        }
+        match (self.line_crossing.from, self.line_crossing.to) {
+            (Some(from), Some(to)) if from == to => {
+                return Err(ConfigError::Invalid(format!(
+                    "line_crossing.from and line_crossing.to must be different points, both are {:?}",
+                    from
+                )));
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                return Err(ConfigError::Invalid(
+                    "line_crossing.from and line_crossing.to must be set together".to_string(),
+                ));
+            }
+            _ => {}
+        }
+        if self.line_crossing.hysteresis < 0.0 {
+            return Err(ConfigError::Invalid(format!(
+                "line_crossing.hysteresis must not be negative, got {}",
+                self.line_crossing.hysteresis
+            )));
+        }
+        validate_zones(&self.zones, "zones")?;
+        for (i, camera) in self.cameras.iter().enumerate() {
+            if camera.device_id.is_empty() || camera.device_id.contains(['/', '+', '#']) {
+                return Err(ConfigError::Invalid(format!(
+                    "cameras[{}].device_id must be a single topic level without wildcards, got '{}'",
+                    i, camera.device_id
+                )));
+            }
+            if self.cameras[..i].iter().any(|other| other.device_id == camera.device_id) {
+                return Err(ConfigError::Invalid(format!(
+                    "camera device_id {} is used twice",
+                    camera.device_id
+                )));
+            }
+            if let CameraSource::Pipeline { gstreamer } = &camera.source {
+                if gstreamer.trim().is_empty() {
+                    return Err(ConfigError::Invalid(format!(
+                        "cameras[{}].source.gstreamer must not be empty",
+                        i
+                    )));
+                }
+            }
+            validate_zones(&camera.zones, &format!("cameras[{}].zones", i))?;
+            let width = camera.frame_width.unwrap_or(self.camera.frame_width);
+            let height = camera.frame_height.unwrap_or(self.camera.frame_height);
+            if width <= 0.0 || height <= 0.0 {
+                return Err(ConfigError::Invalid(format!(
+                    "camera {} must have a positive resolution, got {}x{}",
+                    camera.device_id, width, height
+                )));
+            }
+        }
+        if let Some(roi) = &self.detector.roi {
+            if roi.x < 0 || roi.y < 0 || roi.width <= 0 || roi.height <= 0 {
+                return Err(ConfigError::Invalid(format!(
+                    "detector.roi must start inside the frame and have a positive size, got {}x{} at ({}, {})",
+                    roi.width, roi.height, roi.x, roi.y
+                )));
+            }
+            // Streams and files are read at their own resolution, checked by the detector
+            if let CameraSource::DeviceIndex(_) = self.camera.source {
+                // The region is in the rotated frame
+                let (frame_width, frame_height) = match self.camera.rotate {
+                    90 | 270 => (self.camera.frame_height, self.camera.frame_width),
+                    _ => (self.camera.frame_width, self.camera.frame_height),
+                };
+                if f64::from(roi.x + roi.width) > frame_width || f64::from(roi.y + roi.height) > frame_height {
+                    return Err(ConfigError::Invalid(format!(
+                        "detector.roi {}x{} at ({}, {}) doesn't fit into the {}x{} frame",
+                        roi.width, roi.height, roi.x, roi.y, frame_width, frame_height
+                    )));
+                }
+            }
+        }
+        if self.detector.detector_type == DetectorType::Hog {
+            let channels = preprocessing::output_channels(&self.preprocessing.steps)
+                .map_err(|e| ConfigError::Invalid(format!("preprocessing.steps: {}", e)))?;
+            if channels != 1 {
+                return Err(ConfigError::Invalid(
+                    "preprocessing.steps must end with a single-channel image for the HOG detector, add a gray_scale step"
+                        .to_string(),
+                ));
+            }
+        }
+        if self.detector.detector_type == DetectorType::Mog2 {
+            if self.mog2.history <= 0 {
+                return Err(ConfigError::Invalid(format!(
+                    "mog2.history must be positive, got {}",
+                    self.mog2.history
+                )));
+            }
+            if self.mog2.var_threshold <= 0.0 {
+                return Err(ConfigError::Invalid(format!(
+                    "mog2.var_threshold must be positive, got {}",
+                    self.mog2.var_threshold
+                )));
+            }
+            if self.mog2.min_area < 0.0 {
+                return Err(ConfigError::Invalid(format!(
+                    "mog2.min_area must not be negative, got {}",
+                    self.mog2.min_area
+                )));
+            }
+        }
+        if self.detector.detector_type == DetectorType::Yolo && self.yolo.input_size <= 0 {
+            return Err(ConfigError::Invalid(format!(
+                "yolo.input_size must be positive, got {}",
+                self.yolo.input_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::from_table(toml::Table::new(), "built-in defaults")
+            .expect("built-in default config is valid")
+    }
+}
+
+// highgui needs an X11 or Wayland session to open its window
+fn display_available() -> bool {
+    ["DISPLAY", "WAYLAND_DISPLAY"]
+        .iter()
+        .any(|var| std::env::var_os(var).is_some_and(|value| !value.is_empty()))
+}
+
+fn parse_rotation(value: &str) -> Result<u16, String> {
+    match value.parse() {
+        Ok(degrees @ (0 | 90 | 180 | 270)) => Ok(degrees),
+        _ => Err(format!("expected 0, 90, 180 or 270, got '{}'", value)),
+    }
+}
+
+// Clap parser for sizes given as `8x8` or `8,8`
+fn parse_size(value: &str) -> Result<(i32, i32), String> {
+    let (width, height) = value
+        .split_once(['x', ','])
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, e.g. 8x8, got '{}'", value))?;
+    let parse = |part: &str| {
+        part.trim()
+            .parse::<i32>()
+            .map_err(|_| format!("expected WIDTHxHEIGHT, e.g. 8x8, got '{}'", value))
+    };
+    Ok((parse(width)?, parse(height)?))
+}
+
+fn parse_positional<T: std::str::FromStr>(value: &str, name: &str) -> Result<T, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::Args(format!("invalid value '{}' for {}", value, name)))
+}
+
+fn read_table(path: &Path) -> Result<toml::Table, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    parse_table(&contents, &path.display().to_string())
+}
+
+fn parse_table(contents: &str, origin: &str) -> Result<toml::Table, ConfigError> {
+    contents.parse::<toml::Table>().map_err(|source| ConfigError::Parse {
+        origin: origin.to_string(),
+        source,
+    })
+}
+
+// Recursively overlays `overlay` on `base`, so partial files keep the defaults
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(overlay_table)) => {
+                merge_tables(base_table, overlay_table);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn flatten_table(table: &toml::Table, prefix: &str, out: &mut Vec<(String, toml::Value)>) {
+    for (key, value) in table {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            toml::Value::Table(inner) => flatten_table(inner, &path, out),
+            value => out.push((path, value.clone())),
+        }
+    }
+}
+
+fn lookup<'a>(table: &'a toml::Table, dotted_key: &str) -> Option<&'a toml::Value> {
+    let mut parts = dotted_key.split('.');
+    let mut value = table.get(parts.next()?)?;
+    for part in parts {
+        value = value.as_table()?.get(part)?;
+    }
+    Some(value)
+}
+
+// `key` names the list in errors, e.g. cameras[1].zones
+fn validate_zones(zones: &[Zone], key: &str) -> Result<(), ConfigError> {
+    for (i, zone) in zones.iter().enumerate() {
+        if zone.name.trim().is_empty() {
+            return Err(ConfigError::Invalid(format!("{}[{}].name must not be empty", key, i)));
+        }
+        if zone.width <= 0 || zone.height <= 0 {
+            return Err(ConfigError::Invalid(format!(
+                "zone {} must have a positive size, got {}x{}",
+                zone.name, zone.width, zone.height
+            )));
+        }
+        if zones[..i].iter().any(|other| other.name == zone.name) {
+            return Err(ConfigError::Invalid(format!("zone name {} is used twice", zone.name)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Config has no PartialEq, the TOML tables hold every field that can be set
+    fn as_table(config: &Config) -> toml::Table {
+        toml::Table::try_from(config).expect("config serializes to TOML")
+    }
+
+    fn write_temp(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}_{}.toml", name, std::process::id()));
+        fs::write(&path, contents).expect("temp config is writable");
+        path
+    }
+
+    #[test]
+    fn default_config_round_trips_through_the_loader() {
+        let defaults = Config::default();
+        let path = write_temp("round_trip", &toml::to_string(&defaults).expect("defaults serialize"));
+        let loaded = Config::from_file(&path);
+        fs::remove_file(&path).ok();
+
+        assert_eq!(as_table(&loaded.expect("serialized defaults load")), as_table(&defaults));
+    }
+
+    #[test]
+    fn partial_file_keeps_the_other_defaults() {
+        let path = write_temp("partial", "[broker]\nport = 8883\n\n[hog]\nnms_threshold = 0.5\n");
+        let loaded = Config::from_file(&path);
+        fs::remove_file(&path).ok();
+        let loaded = loaded.expect("partial config loads");
+
+        let defaults = Config::default();
+        assert_eq!(loaded.broker.port, 8883);
+        assert_eq!(loaded.hog.nms_threshold, 0.5);
+        assert_eq!(loaded.broker.ip, defaults.broker.ip);
+        assert_eq!(loaded.broker.topic, defaults.broker.topic);
+
+        let mut expected = as_table(&defaults);
+        expected["broker"]["port"] = toml::Value::Integer(8883);
+        expected["hog"]["nms_threshold"] = toml::Value::Float(0.5);
+        assert_eq!(as_table(&loaded), expected);
+    }
+
+    #[test]
+    fn unknown_key_is_rejected() {
+        let path = write_temp("unknown_key", "[broker]\nprot = 8883\n");
+        let loaded = Config::from_file(&path);
+        fs::remove_file(&path).ok();
+
+        assert!(matches!(loaded, Err(ConfigError::Parse { .. })));
+    }
+
+    #[test]
+    fn command_line_overrides_are_validated_with_the_file() {
+        let path = write_temp("cli_override", "[broker]\nport = 0\n");
+        let config = path.to_str().expect("temp path is UTF-8").to_string();
+        let cli = |args: &[&str]| {
+            let program = ["raspberrypi_people_detection", "--config", &config];
+            Cli::parse_from(program.iter().chain(args))
+        };
+        let fixed = Config::from_cli(cli(&["--port", "1884"]));
+        let invalid = Config::from_cli(cli(&[]));
+        fs::remove_file(&path).ok();
+
+        assert_eq!(fixed.expect("--port replaces the invalid port").broker.port, 1884);
+        assert!(matches!(invalid, Err(ConfigError::Invalid(_))));
+    }
+}
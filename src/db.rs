@@ -0,0 +1,143 @@
+use rusqlite::{params, Connection};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinHandle};
+
+use crate::error::AppError;
+use crate::event::DetectionEvent;
+
+// Rows waiting for the writer thread, a slow SD card catches up in batches
+const QUEUE_CAPACITY: usize = 256;
+
+const MIGRATION: &str = "
+    CREATE TABLE IF NOT EXISTS events (
+        id INTEGER PRIMARY KEY,
+        ts TEXT,
+        count INTEGER,
+        boxes_json TEXT
+    );
+    CREATE INDEX IF NOT EXISTS events_ts ON events (ts);
+";
+
+struct Row {
+    timestamp: String,
+    count: usize,
+    boxes_json: String,
+}
+
+// Local audit trail of every detection pass, kept even while the broker is
+// unreachable. Inserts happen on a blocking thread so the detection loop
+// only ever pays for queueing the row.
+pub struct EventStore {
+    rows: mpsc::Sender<Row>,
+    writer: JoinHandle<()>,
+}
+
+impl EventStore {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = open(path)?;
+        let (rows, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        Ok(EventStore {
+            rows,
+            writer: task::spawn_blocking(move || write_rows(connection, receiver)),
+        })
+    }
+
+    // Never blocks, the row is dropped when the writer has fallen that far behind
+    pub fn insert(&self, event: &DetectionEvent) {
+        let boxes_json = match serde_json::to_string(&event.bounding_boxes) {
+            Ok(json) => json,
+            Err(e) => {
+                log::warn!("Failed to serialize bounding boxes: {}", e);
+                return;
+            }
+        };
+        let row = Row {
+            timestamp: event.timestamp.clone(),
+            count: event.count,
+            boxes_json,
+        };
+        if self.rows.try_send(row).is_err() {
+            log::warn!("Database writer is behind, dropping detection event");
+        }
+    }
+
+    // Waits until the queued rows are written
+    pub async fn close(self) {
+        drop(self.rows);
+        if let Err(e) = self.writer.await {
+            log::error!("Database writer failed: {}", e);
+        }
+    }
+}
+
+fn open(path: &Path) -> rusqlite::Result<Connection> {
+    let connection = Connection::open(path)?;
+    // Readers such as `export-csv` don't block the writer
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.execute_batch(MIGRATION)?;
+    Ok(connection)
+}
+
+fn write_rows(mut connection: Connection, mut receiver: mpsc::Receiver<Row>) {
+    while let Some(row) = receiver.blocking_recv() {
+        // Whatever queued up meanwhile goes into the same transaction
+        let mut batch = vec![row];
+        while let Ok(row) = receiver.try_recv() {
+            batch.push(row);
+        }
+        if let Err(e) = insert_batch(&mut connection, &batch) {
+            log::warn!("Failed to write {} detection events to the database: {}", batch.len(), e);
+        }
+    }
+}
+
+fn insert_batch(connection: &mut Connection, rows: &[Row]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut insert =
+            transaction.prepare_cached("INSERT INTO events (ts, count, boxes_json) VALUES (?1, ?2, ?3)")?;
+        for row in rows {
+            insert.execute(params![row.timestamp, row.count as i64, row.boxes_json])?;
+        }
+    }
+    transaction.commit()
+}
+
+// `export-csv`: every stored event ordered by time, returns the number of rows
+pub fn export_csv(db_path: &Path, output: &Path) -> Result<usize, AppError> {
+    let io_error = |source| AppError::Io {
+        path: output.to_path_buf(),
+        source,
+    };
+
+    let connection = Connection::open(db_path).map_err(AppError::Database)?;
+    let mut query = connection
+        .prepare("SELECT id, ts, count, boxes_json FROM events ORDER BY ts, id")
+        .map_err(AppError::Database)?;
+    let mut rows = query.query([]).map_err(AppError::Database)?;
+
+    let mut writer = BufWriter::new(File::create(output).map_err(io_error)?);
+    writeln!(writer, "id,ts,count,boxes_json").map_err(io_error)?;
+    let mut exported = 0;
+    while let Some(row) = rows.next().map_err(AppError::Database)? {
+        let id: i64 = row.get(0).map_err(AppError::Database)?;
+        let timestamp: String = row.get(1).map_err(AppError::Database)?;
+        let count: i64 = row.get(2).map_err(AppError::Database)?;
+        let boxes_json: String = row.get(3).map_err(AppError::Database)?;
+        writeln!(
+            writer,
+            "{},{},{},\"{}\"",
+            id,
+            timestamp,
+            count,
+            boxes_json.replace('"', "\"\"")
+        )
+        .map_err(io_error)?;
+        exported += 1;
+    }
+    writer.flush().map_err(io_error)?;
+    Ok(exported)
+}
@@ -0,0 +1,73 @@
+// Holds back a new value until it was seen `stable_frames` times in a row,
+// so a person half hidden in a doorway doesn't flip the count 0, 1, 0, 1
+// and send a message on every flip
+#[derive(Debug)]
+pub struct Debouncer {
+    stable_frames: u32,
+    value: usize,
+    candidate: usize,
+    streak: u32,
+}
+
+impl Debouncer {
+    // Starts out at `initial`, `stable_frames` 1 passes every value through
+    pub fn new(stable_frames: u32, initial: usize) -> Self {
+        Debouncer {
+            stable_frames,
+            value: initial,
+            candidate: initial,
+            streak: 0,
+        }
+    }
+
+    // Feeds the instantaneous value, returns the debounced one
+    pub fn update(&mut self, raw: usize) -> usize {
+        if raw == self.candidate {
+            self.streak = self.streak.saturating_add(1);
+        } else {
+            self.candidate = raw;
+            self.streak = 1;
+        }
+        if self.streak >= self.stable_frames {
+            self.value = raw;
+        }
+        self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flicker_shorter_than_stable_frames_is_suppressed() {
+        let mut debouncer = Debouncer::new(3, 0);
+        for raw in [1, 0, 1, 1, 0, 1, 0] {
+            assert_eq!(debouncer.update(raw), 0);
+        }
+    }
+
+    #[test]
+    fn stable_change_is_accepted_after_stable_frames() {
+        let mut debouncer = Debouncer::new(3, 0);
+        assert_eq!(debouncer.update(2), 0);
+        assert_eq!(debouncer.update(2), 0);
+        assert_eq!(debouncer.update(2), 2);
+        assert_eq!(debouncer.update(2), 2);
+    }
+
+    #[test]
+    fn count_goes_up_and_back_to_zero() {
+        let mut debouncer = Debouncer::new(2, 0);
+        let counts: Vec<usize> = [3, 3, 3, 0, 0, 0].into_iter().map(|raw| debouncer.update(raw)).collect();
+        assert_eq!(counts, [0, 3, 3, 3, 0, 0]);
+    }
+
+    #[test]
+    fn one_stable_frame_passes_every_value() {
+        let mut debouncer = Debouncer::new(1, 0);
+        for raw in [1, 0, 4, 2] {
+            assert_eq!(debouncer.update(raw), raw);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use opencv::{core::Mat, prelude::*, types::VectorOfRect};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+
+use crate::config::Config;
+use crate::detector::create_detector;
+use crate::event::FrameSize;
+
+// Result of running the detector on one frame
+pub struct DetectionPass {
+    pub boxes: VectorOfRect,
+    pub latency: Duration,
+    // Size of the frame the boxes refer to
+    pub frame: FrameSize,
+    // When that frame was read from the camera
+    pub captured_at: Instant,
+    // The submitted copy, kept by the worker for the next `submit`
+    image: Mat,
+}
+
+// Runs the detector on a blocking thread so a 100-500 ms HOG pass doesn't
+// stall MQTT keep-alives and timers on the async side. Only one frame is in
+// flight at a time, frames arriving meanwhile aren't detected, which keeps the
+// latency bounded instead of building up a queue.
+pub struct DetectionWorker {
+    frames: mpsc::Sender<(Mat, Instant)>,
+    passes: mpsc::Receiver<opencv::Result<DetectionPass>>,
+    busy: bool,
+    // Buffer the next frame is copied into, at a fixed resolution that
+    // reuses the allocation of the previous copy
+    spare: Mat,
+}
+
+impl DetectionWorker {
+    // The detector is created on the worker thread, OpenCV's detectors can't
+    // be moved between threads. Fails when it can't be created.
+    pub async fn spawn(config: &Config) -> opencv::Result<Self> {
+        let config = config.clone();
+        let (frames, mut requests) = mpsc::channel::<(Mat, Instant)>(1);
+        let (results, passes) = mpsc::channel(1);
+        let (created, creation) = oneshot::channel();
+        // Ends once the worker is dropped
+        task::spawn_blocking(move || {
+            let mut detector = match create_detector(&config) {
+                Ok(detector) => {
+                    let _ = created.send(Ok(()));
+                    detector
+                }
+                Err(e) => {
+                    let _ = created.send(Err(e));
+                    return;
+                }
+            };
+            while let Some((frame, captured_at)) = requests.blocking_recv() {
+                let started = Instant::now();
+                let pass = detector.detect(&frame).map(|boxes| DetectionPass {
+                    boxes,
+                    latency: started.elapsed(),
+                    frame: FrameSize {
+                        width: frame.cols(),
+                        height: frame.rows(),
+                    },
+                    captured_at,
+                    image: frame,
+                });
+                if results.blocking_send(pass).is_err() {
+                    break;
+                }
+            }
+        });
+        creation.await.unwrap_or_else(|_| {
+            Err(opencv::Error::new(
+                opencv::core::StsError,
+                "the detection thread stopped".to_string(),
+            ))
+        })?;
+        Ok(DetectionWorker {
+            frames,
+            passes,
+            busy: false,
+            spare: Mat::default(),
+        })
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.busy
+    }
+
+    // Hands a copy of the frame to the detector, false when it is still busy
+    // with the last one. `captured_at` is passed through to the result.
+    pub fn submit(&mut self, frame: &Mat, captured_at: Instant) -> opencv::Result<bool> {
+        if self.busy {
+            return Ok(false);
+        }
+        let mut image = std::mem::take(&mut self.spare);
+        frame.copy_to(&mut image)?;
+        if self.frames.try_send((image, captured_at)).is_err() {
+            return Ok(false);
+        }
+        self.busy = true;
+        Ok(true)
+    }
+
+    // Waits for the frame in flight, None when the detection thread has
+    // stopped. Safe to use in `select!`, a pass is never lost.
+    pub async fn finished(&mut self) -> Option<opencv::Result<DetectionPass>> {
+        let mut pass = self.passes.recv().await;
+        self.busy = false;
+        if let Some(Ok(pass)) = &mut pass {
+            self.spare = std::mem::take(&mut pass.image);
+        }
+        pass
+    }
+}
@@ -0,0 +1,584 @@
+use opencv::{
+    core::{self, Mat, Point, Ptr, Rect, Scalar, Size, Vector},
+    dnn, imgproc,
+    objdetect::HOGDescriptor,
+    prelude::*,
+    types::{VectorOfRect, VectorOfVectorOfPoint},
+    video::{self, BackgroundSubtractorMOG2},
+};
+use std::path::Path;
+
+use crate::config::{Config, DetectorConfig, DetectorType, DnnParams, HogParams, Mog2Params, Roi, YoloParams};
+use crate::preprocessing::{PreprocessStep, Preprocessor};
+
+// Finds people in a BGR camera frame, boxes are in frame pixel coordinates
+pub trait Detector {
+    fn detect(&mut self, frame: &Mat) -> opencv::Result<VectorOfRect>;
+}
+
+pub fn create_detector(config: &Config) -> opencv::Result<Box<dyn Detector>> {
+    let detector: Box<dyn Detector> = match config.detector.detector_type {
+        DetectorType::Hog => Box::new(HogDetector::new(
+            config.hog.clone(),
+            config.preprocessing.steps.clone(),
+        )?),
+        DetectorType::Yolo => Box::new(YoloDetector::new(&config.yolo)?),
+        DetectorType::Dnn => Box::new(DnnDetector::new(&config.dnn)?),
+        DetectorType::Mog2 => Box::new(BackgroundSubtractorDetector::new(&config.mog2)?),
+    };
+    let detector: Box<dyn Detector> = match config.detector.roi {
+        Some(roi) => Box::new(RoiDetector::new(detector, roi)),
+        None => detector,
+    };
+    Ok(Box::new(SizeFilter::new(detector, &config.detector)))
+}
+
+// Drops boxes too small, too large or too wide to be a person, e.g. a lamp
+// reflection or a shadow across the floor
+pub struct SizeFilter {
+    detector: Box<dyn Detector>,
+    min: (i32, i32),
+    max: (i32, i32),
+    aspect_ratio: (f64, f64),
+}
+
+impl SizeFilter {
+    pub fn new(detector: Box<dyn Detector>, config: &DetectorConfig) -> Self {
+        SizeFilter {
+            detector,
+            min: config.min_person_bbox.unwrap_or((0, 0)),
+            max: config.max_person_bbox.unwrap_or((i32::MAX, i32::MAX)),
+            aspect_ratio: (config.aspect_ratio_min, config.aspect_ratio_max),
+        }
+    }
+
+    fn plausible(&self, person: &Rect) -> bool {
+        if person.width < self.min.0 || person.height < self.min.1 {
+            return false;
+        }
+        if person.width > self.max.0 || person.height > self.max.1 {
+            return false;
+        }
+        let aspect_ratio = person.width as f64 / person.height.max(1) as f64;
+        (self.aspect_ratio.0..=self.aspect_ratio.1).contains(&aspect_ratio)
+    }
+}
+
+impl Detector for SizeFilter {
+    fn detect(&mut self, frame: &Mat) -> opencv::Result<VectorOfRect> {
+        Ok(self
+            .detector
+            .detect(frame)?
+            .iter()
+            .filter(|person| {
+                let plausible = self.plausible(person);
+                if !plausible {
+                    log::debug!(
+                        "Ignoring implausible box {}x{} at ({}, {})",
+                        person.width,
+                        person.height,
+                        person.x,
+                        person.y
+                    );
+                }
+                plausible
+            })
+            .collect())
+    }
+}
+
+// Runs the detector on the region of interest only, people outside it are
+// never seen. Boxes are moved back to full frame coordinates.
+pub struct RoiDetector {
+    detector: Box<dyn Detector>,
+    roi: Rect,
+}
+
+impl RoiDetector {
+    pub fn new(detector: Box<dyn Detector>, roi: Roi) -> Self {
+        RoiDetector {
+            detector,
+            roi: Rect::new(roi.x, roi.y, roi.width, roi.height),
+        }
+    }
+}
+
+impl Detector for RoiDetector {
+    fn detect(&mut self, frame: &Mat) -> opencv::Result<VectorOfRect> {
+        let bounds = Rect::new(0, 0, frame.cols(), frame.rows());
+        if (self.roi & bounds) != self.roi {
+            return Err(opencv::Error::new(
+                core::StsOutOfRange,
+                format!(
+                    "detector.roi {}x{} at ({}, {}) doesn't fit into the {}x{} frame",
+                    self.roi.width,
+                    self.roi.height,
+                    self.roi.x,
+                    self.roi.y,
+                    bounds.width,
+                    bounds.height
+                ),
+            ));
+        }
+        let region = Mat::roi(frame, self.roi)?;
+        Ok(self
+            .detector
+            .detect(&region)?
+            .iter()
+            .map(|person| Rect::new(person.x + self.roi.x, person.y + self.roi.y, person.width, person.height))
+            .collect())
+    }
+}
+
+pub struct HogDetector {
+    hog: HOGDescriptor,
+    params: HogParams,
+    // Config validation made sure the steps end with a single-channel image
+    preprocessor: Preprocessor,
+    // Reused on every pass, see `Preprocessor`
+    small: Mat,
+    found: VectorOfRect,
+}
+
+impl HogDetector {
+    pub fn new(params: HogParams, steps: Vec<PreprocessStep>) -> opencv::Result<Self> {
+        let mut hog = HOGDescriptor::default()?;
+        hog.set_svm_detector(&HOGDescriptor::get_default_people_detector()?)?;
+        Ok(HogDetector {
+            hog,
+            params,
+            preprocessor: Preprocessor::new(steps)?,
+            small: Mat::default(),
+            found: VectorOfRect::new(),
+        })
+    }
+}
+
+impl Detector for HogDetector {
+    fn detect(&mut self, frame: &Mat) -> opencv::Result<VectorOfRect> {
+        let input = match detect_size(&self.params, frame.size()?) {
+            Some(size) => {
+                imgproc::resize(frame, &mut self.small, size, 0.0, 0.0, imgproc::INTER_AREA)?;
+                &self.small
+            }
+            None => frame,
+        };
+        let image = self.preprocessor.apply(input)?;
+
+        let boxes = &mut self.found;
+        boxes.clear();
+        let params = &self.params;
+        self.hog.detect_multi_scale(
+            image,
+            boxes,
+            params.hit_threshold,
+            Size::new(params.win_stride.0, params.win_stride.1),
+            Size::new(params.padding.0, params.padding.1),
+            params.scale,
+            params.final_threshold,
+            params.use_meanshift_grouping,
+        )?;
+
+        // Back through the resizing and rotating preprocessing steps
+        let mut boxes = self.preprocessor.map_back(&boxes.to_vec());
+        // Scaled per axis from the actual sizes, the downscaled size was rounded
+        if input.size()? != frame.size()? {
+            boxes = scale_rects(
+                &boxes,
+                frame.cols() as f64 / input.cols() as f64,
+                frame.rows() as f64 / input.rows() as f64,
+            );
+        }
+
+        // HOG often reports several overlapping boxes for one person
+        Ok(non_max_suppression(&boxes, params.nms_threshold)
+            .into_iter()
+            .collect())
+    }
+}
+
+// Size HOG runs at for a frame of `frame` pixels, None keeps the frame as it
+// is. A single width or height keeps the aspect ratio, both may stretch it.
+// Frames are only ever made smaller.
+fn detect_size(params: &HogParams, frame: Size) -> Option<Size> {
+    let size = match (params.detect_scale, params.detect_width, params.detect_height) {
+        (Some(factor), _, _) => Size::new(
+            (frame.width as f64 * factor).round() as i32,
+            (frame.height as f64 * factor).round() as i32,
+        ),
+        (None, Some(width), Some(height)) => Size::new(width, height),
+        (None, Some(width), None) => Size::new(
+            width,
+            (frame.height as f64 * width as f64 / frame.width as f64).round() as i32,
+        ),
+        (None, None, Some(height)) => Size::new(
+            (frame.width as f64 * height as f64 / frame.height as f64).round() as i32,
+            height,
+        ),
+        (None, None, None) => return None,
+    };
+    let size = Size::new(size.width.clamp(1, frame.width), size.height.clamp(1, frame.height));
+    (size != frame).then_some(size)
+}
+
+// Maps boxes found on a resized image back to the original, rounding each
+// corner separately so the boxes line up with the full resolution frame
+pub fn scale_rects(boxes: &[Rect], scale_x: f64, scale_y: f64) -> Vec<Rect> {
+    boxes
+        .iter()
+        .map(|rect| {
+            let left = (rect.x as f64 * scale_x).round() as i32;
+            let top = (rect.y as f64 * scale_y).round() as i32;
+            let right = ((rect.x + rect.width) as f64 * scale_x).round() as i32;
+            let bottom = ((rect.y + rect.height) as f64 * scale_y).round() as i32;
+            Rect::new(left, top, right - left, bottom - top)
+        })
+        .collect()
+}
+
+// Share of a box inside a larger one that makes it part of the same person,
+// HOG likes to add a torso box inside the full body box. Their IoU is often
+// below any threshold that still keeps two people standing close apart.
+const CONTAINED_FRACTION: f64 = 0.9;
+
+// Keeps the largest of every group of boxes overlapping by more than
+// `iou_threshold` or lying inside each other, HOG gives no usable scores so
+// size decides. Identical boxes count once. A threshold of 1.0 keeps all.
+pub fn non_max_suppression(boxes: &[Rect], iou_threshold: f64) -> Vec<Rect> {
+    if iou_threshold >= 1.0 {
+        return boxes.to_vec();
+    }
+    let mut candidates = boxes.to_vec();
+    // Stable, of identical boxes the first one reported is kept
+    candidates.sort_by_key(|rect| std::cmp::Reverse(rect.area()));
+
+    let mut kept: Vec<Rect> = Vec::with_capacity(candidates.len());
+    for candidate in candidates {
+        if kept.iter().all(|rect| {
+            iou(rect, &candidate) <= iou_threshold && contained(&candidate, rect) < CONTAINED_FRACTION
+        }) {
+            kept.push(candidate);
+        }
+    }
+    kept
+}
+
+// Share of `inner` covered by `outer`, 0 for an empty box
+fn contained(inner: &Rect, outer: &Rect) -> f64 {
+    let area = inner.area() as f64;
+    if area <= 0.0 {
+        return 0.0;
+    }
+    (*inner & *outer).area() as f64 / area
+}
+
+// Intersection over union of two boxes, 0 when they don't touch
+fn iou(a: &Rect, b: &Rect) -> f64 {
+    let intersection = (*a & *b).area() as f64;
+    let union = a.area() as f64 + b.area() as f64 - intersection;
+    if union <= 0.0 {
+        return 0.0;
+    }
+    intersection / union
+}
+
+// COCO class index of "person" in the YOLOv8 output
+const YOLO_PERSON_CLASS: usize = 0;
+
+pub struct YoloDetector {
+    net: dnn::Net,
+    input_size: i32,
+    confidence_threshold: f32,
+    nms_threshold: f32,
+}
+
+impl YoloDetector {
+    pub fn new(params: &YoloParams) -> opencv::Result<Self> {
+        require_file(&params.model_path, "YOLO model")?;
+
+        let mut net = dnn::read_net_from_onnx(&params.model_path)?;
+        net.set_preferable_backend(dnn::DNN_BACKEND_OPENCV)?;
+        net.set_preferable_target(dnn::DNN_TARGET_CPU)?;
+
+        Ok(YoloDetector {
+            net,
+            input_size: params.input_size,
+            confidence_threshold: params.confidence_threshold,
+            nms_threshold: params.nms_threshold,
+        })
+    }
+}
+
+impl Detector for YoloDetector {
+    fn detect(&mut self, frame: &Mat) -> opencv::Result<VectorOfRect> {
+        let blob = dnn::blob_from_image(
+            frame,
+            1.0 / 255.0,
+            Size::new(self.input_size, self.input_size),
+            Scalar::default(),
+            true,
+            false,
+            core::CV_32F,
+        )?;
+        self.net.set_input(&blob, "", 1.0, Scalar::default())?;
+        let output = self.net.forward_single("")?;
+
+        // YOLOv8 output is [1, 4 + classes, candidates] with rows cx, cy, w, h, class scores
+        let dims = output.mat_size();
+        if dims.len() != 3 || (dims[1] as usize) <= 4 + YOLO_PERSON_CLASS {
+            return Err(opencv::Error::new(
+                core::StsUnmatchedSizes,
+                format!("unexpected YOLO output shape {:?}", &*dims),
+            ));
+        }
+        let candidates = dims[2] as usize;
+        let data = output.data_typed::<f32>()?;
+        let row = |attribute: usize| &data[attribute * candidates..(attribute + 1) * candidates];
+        let (cx, cy, w, h) = (row(0), row(1), row(2), row(3));
+        let person_scores = row(4 + YOLO_PERSON_CLASS);
+
+        // The blob is stretched to the input size, so scale back per axis
+        let x_factor = frame.cols() as f32 / self.input_size as f32;
+        let y_factor = frame.rows() as f32 / self.input_size as f32;
+
+        let mut boxes = VectorOfRect::new();
+        let mut scores = Vector::<f32>::new();
+        for i in 0..candidates {
+            let score = person_scores[i];
+            if score < self.confidence_threshold {
+                continue;
+            }
+            boxes.push(Rect::new(
+                ((cx[i] - w[i] / 2.0) * x_factor).round() as i32,
+                ((cy[i] - h[i] / 2.0) * y_factor).round() as i32,
+                (w[i] * x_factor).round() as i32,
+                (h[i] * y_factor).round() as i32,
+            ));
+            scores.push(score);
+        }
+
+        let mut indices = Vector::<i32>::new();
+        dnn::nms_boxes(
+            &boxes,
+            &scores,
+            self.confidence_threshold,
+            self.nms_threshold,
+            &mut indices,
+            1.0,
+            0,
+        )?;
+
+        indices.iter().map(|i| boxes.get(i as usize)).collect()
+    }
+}
+
+// VOC class index of "person" in the MobileNet-SSD output
+const SSD_PERSON_CLASS: f32 = 15.0;
+// Network input the Caffe MobileNet-SSD was trained with
+const SSD_INPUT_SIZE: i32 = 300;
+const SSD_SCALE: f64 = 0.007843;
+const SSD_MEAN: f64 = 127.5;
+
+pub struct DnnDetector {
+    net: dnn::Net,
+    confidence_threshold: f32,
+}
+
+impl DnnDetector {
+    pub fn new(params: &DnnParams) -> opencv::Result<Self> {
+        require_file(&params.prototxt_path, "MobileNet-SSD prototxt")?;
+        require_file(&params.model_path, "MobileNet-SSD model")?;
+
+        let mut net = dnn::read_net_from_caffe(&params.prototxt_path, &params.model_path)?;
+        net.set_preferable_backend(dnn::DNN_BACKEND_OPENCV)?;
+        net.set_preferable_target(dnn::DNN_TARGET_CPU)?;
+
+        Ok(DnnDetector {
+            net,
+            confidence_threshold: params.confidence_threshold,
+        })
+    }
+}
+
+impl Detector for DnnDetector {
+    fn detect(&mut self, frame: &Mat) -> opencv::Result<VectorOfRect> {
+        let blob = dnn::blob_from_image(
+            frame,
+            SSD_SCALE,
+            Size::new(SSD_INPUT_SIZE, SSD_INPUT_SIZE),
+            Scalar::all(SSD_MEAN),
+            false,
+            false,
+            core::CV_32F,
+        )?;
+        self.net.set_input(&blob, "", 1.0, Scalar::default())?;
+        let output = self.net.forward_single("")?;
+
+        // SSD output is [1, 1, detections, 7] with rows
+        // image id, class, confidence, x1, y1, x2, y2 (relative to the frame)
+        let dims = output.mat_size();
+        if dims.len() != 4 || dims[3] != 7 {
+            return Err(opencv::Error::new(
+                core::StsUnmatchedSizes,
+                format!("unexpected MobileNet-SSD output shape {:?}", &*dims),
+            ));
+        }
+        let data = output.data_typed::<f32>()?;
+
+        let width = frame.cols() as f32;
+        let height = frame.rows() as f32;
+        let mut boxes = VectorOfRect::new();
+        for detection in data.chunks_exact(7) {
+            if detection[1] != SSD_PERSON_CLASS || detection[2] < self.confidence_threshold {
+                continue;
+            }
+            let x1 = (detection[3] * width).round() as i32;
+            let y1 = (detection[4] * height).round() as i32;
+            let x2 = (detection[5] * width).round() as i32;
+            let y2 = (detection[6] * height).round() as i32;
+            boxes.push(Rect::new(x1, y1, x2 - x1, y2 - y1));
+        }
+        Ok(boxes)
+    }
+}
+
+fn require_file(path: &str, what: &str) -> opencv::Result<()> {
+    if Path::new(path).exists() {
+        return Ok(());
+    }
+    Err(opencv::Error::new(
+        core::StsObjectNotFound,
+        format!("{} not found: {}", what, path),
+    ))
+}
+
+// Foreground mask value of a pixel MOG2 marked as shadow, moving pixels are 255
+const SHADOW_VALUE: f64 = 127.0;
+
+// Reports blobs that differ from a learned background, cheap and reliable for
+// a static camera but blind to people who stand still long enough to become
+// part of the background
+pub struct BackgroundSubtractorDetector {
+    subtractor: Ptr<dyn BackgroundSubtractorMOG2>,
+    min_area: f64,
+    // Removes single-pixel noise from the mask before looking for blobs
+    kernel: Mat,
+    // Reused on every pass, see `Preprocessor`
+    mask: Mat,
+    foreground: Mat,
+    cleaned: Mat,
+    contours: VectorOfVectorOfPoint,
+}
+
+impl BackgroundSubtractorDetector {
+    pub fn new(params: &Mog2Params) -> opencv::Result<Self> {
+        let subtractor =
+            video::create_background_subtractor_mog2(params.history, params.var_threshold, params.detect_shadows)?;
+        let kernel = imgproc::get_structuring_element(imgproc::MORPH_ELLIPSE, Size::new(5, 5), Point::new(-1, -1))?;
+        Ok(BackgroundSubtractorDetector {
+            subtractor,
+            min_area: params.min_area,
+            kernel,
+            mask: Mat::default(),
+            foreground: Mat::default(),
+            cleaned: Mat::default(),
+            contours: VectorOfVectorOfPoint::new(),
+        })
+    }
+}
+
+impl Detector for BackgroundSubtractorDetector {
+    fn detect(&mut self, frame: &Mat) -> opencv::Result<VectorOfRect> {
+        // Both traits have an `apply`, -1 lets OpenCV pick the learning rate from the history
+        BackgroundSubtractorMOG2::apply(&mut self.subtractor, frame, &mut self.mask, -1.0)?;
+
+        // Shadows count as background
+        imgproc::threshold(&self.mask, &mut self.foreground, SHADOW_VALUE, 255.0, imgproc::THRESH_BINARY)?;
+        imgproc::morphology_ex(
+            &self.foreground,
+            &mut self.cleaned,
+            imgproc::MORPH_OPEN,
+            &self.kernel,
+            Point::new(-1, -1),
+            1,
+            core::BORDER_CONSTANT,
+            imgproc::morphology_default_border_value()?,
+        )?;
+
+        self.contours.clear();
+        imgproc::find_contours(
+            &self.cleaned,
+            &mut self.contours,
+            imgproc::RETR_EXTERNAL,
+            imgproc::CHAIN_APPROX_SIMPLE,
+            Point::new(0, 0),
+        )?;
+        let mut boxes = VectorOfRect::new();
+        for contour in &self.contours {
+            if imgproc::contour_area(&contour, false)? >= self.min_area {
+                boxes.push(imgproc::bounding_rect(&contour)?);
+            }
+        }
+        Ok(boxes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nms_merges_a_box_almost_covering_another() {
+        let outer = Rect::new(0, 0, 100, 200);
+        let inner = Rect::new(5, 5, 90, 190);
+        assert_eq!(non_max_suppression(&[inner, outer], 0.5), vec![outer]);
+    }
+
+    #[test]
+    fn nms_merges_partially_overlapping_boxes_above_the_threshold() {
+        // IoU 0.75
+        let first = Rect::new(0, 0, 100, 200);
+        let second = Rect::new(10, 10, 100, 200);
+        assert_eq!(non_max_suppression(&[first, second], 0.5), vec![first]);
+        assert_eq!(non_max_suppression(&[first, second], 0.8), vec![first, second]);
+    }
+
+    #[test]
+    fn nms_keeps_disjoint_boxes() {
+        let left = Rect::new(0, 0, 64, 128);
+        let right = Rect::new(200, 0, 80, 160);
+        // The larger box comes first
+        assert_eq!(non_max_suppression(&[left, right], 0.5), vec![right, left]);
+    }
+
+    #[test]
+    fn nms_merges_a_contained_box_below_the_iou_threshold() {
+        // A torso box inside the full body box, IoU 0.25
+        let body = Rect::new(100, 50, 80, 200);
+        let torso = Rect::new(110, 80, 40, 100);
+        assert_eq!(non_max_suppression(&[torso, body], 0.4), vec![body]);
+    }
+
+    #[test]
+    fn nms_keeps_one_of_identical_boxes() {
+        let person = Rect::new(40, 20, 64, 128);
+        assert_eq!(non_max_suppression(&[person, person, person], 0.4), vec![person]);
+    }
+
+    #[test]
+    fn nms_default_keeps_two_people_side_by_side() {
+        let threshold = Config::default().hog.nms_threshold;
+        assert_eq!(threshold, 0.4);
+        // Shoulder to shoulder, IoU 1/3
+        let left = Rect::new(0, 0, 64, 128);
+        let right = Rect::new(32, 0, 64, 128);
+        assert_eq!(non_max_suppression(&[left, right], threshold), vec![left, right]);
+    }
+
+    #[test]
+    fn nms_threshold_one_keeps_every_box() {
+        let person = Rect::new(40, 20, 64, 128);
+        let torso = Rect::new(50, 40, 40, 60);
+        assert_eq!(non_max_suppression(&[person, person, torso], 1.0), vec![person, person, torso]);
+    }
+}
@@ -0,0 +1,96 @@
+use serde_json::json;
+
+use crate::config::{Config, PayloadFormat};
+use crate::mqtt::{self, Topics, OFFLINE, ONLINE};
+use crate::publisher::Message;
+
+// Home Assistant MQTT discovery, the retained config messages below make a
+// people count sensor and an occupancy binary sensor appear automatically
+pub struct Discovery {
+    pub messages: Vec<Message>,
+    // Remove the entities again on a clean shutdown
+    pub clean: bool,
+}
+
+impl Discovery {
+    pub fn new(config: &Config, topics: &Topics) -> Option<Self> {
+        let ha = &config.home_assistant;
+        if !ha.discovery {
+            return None;
+        }
+
+        // Home Assistant only accepts [a-zA-Z0-9_-] in the node id
+        let node_id: String = config
+            .broker
+            .device_id
+            .clone()
+            .unwrap_or_else(|| mqtt::client_id(&config.broker))
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        let count = match config.broker.payload_format {
+            PayloadFormat::Plain => "value | int",
+            PayloadFormat::Json => "value_json.count",
+        };
+        // JSON availability payloads carry the status in a field
+        let availability_template = match config.broker.availability_format {
+            PayloadFormat::Plain => None,
+            PayloadFormat::Json => Some("{{ value_json.status }}"),
+        };
+        let device = json!({
+            "identifiers": [node_id],
+            "name": config.broker.device_id.as_deref().unwrap_or("People detector"),
+            "model": env!("CARGO_PKG_NAME"),
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+
+        let mut sensor = json!({
+            "name": "People count",
+            "unique_id": format!("{}_people_count", node_id),
+            "state_topic": topics.count,
+            "value_template": format!("{{{{ {} }}}}", count),
+            "unit_of_measurement": "people",
+            "state_class": "measurement",
+            "icon": "mdi:account-group",
+            "availability_topic": topics.availability,
+            "payload_available": ONLINE,
+            "payload_not_available": OFFLINE,
+            "device": device,
+        });
+        let mut occupancy = json!({
+            "name": "Occupancy",
+            "unique_id": format!("{}_occupancy", node_id),
+            "device_class": "occupancy",
+            "state_topic": topics.count,
+            "value_template": format!("{{{{ 'ON' if ({}) > 0 else 'OFF' }}}}", count),
+            "availability_topic": topics.availability,
+            "payload_available": ONLINE,
+            "payload_not_available": OFFLINE,
+            "device": device,
+        });
+
+        if let Some(template) = availability_template {
+            sensor["availability_template"] = json!(template);
+            occupancy["availability_template"] = json!(template);
+        }
+
+        let prefix = &ha.discovery_prefix;
+        Some(Discovery {
+            messages: vec![
+                Message {
+                    topic: format!("{}/sensor/{}/people_count/config", prefix, node_id),
+                    payload: sensor.to_string().into_bytes(),
+                    retain: true,
+                    qos: None,
+                },
+                Message {
+                    topic: format!("{}/binary_sensor/{}/occupancy/config", prefix, node_id),
+                    payload: occupancy.to_string().into_bytes(),
+                    retain: true,
+                    qos: None,
+                },
+            ],
+            clean: ha.discovery_clean,
+        })
+    }
+}
@@ -0,0 +1,222 @@
+use chrono::Local;
+use opencv::{
+    core::{self, Mat, Point, Rect, Scalar},
+    highgui, imgproc,
+    prelude::*,
+    types::VectorOfRect,
+};
+
+use crate::config::{DisplayConfig, OverlayPosition};
+use crate::zone::Zone;
+
+const WINDOW_NAME: &str = "People Detection";
+// Text of the count and time overlay, see `draw_overlay`
+const OVERLAY_THICKNESS: i32 = 2;
+const OVERLAY_MARGIN: i32 = 10;
+const OVERLAY_PADDING: i32 = 6;
+// How much the box behind the text darkens the frame
+const OVERLAY_OPACITY: f64 = 0.5;
+
+// The preview window. Every highgui call goes through here and is skipped in
+// headless mode, so the detector runs without an X server or framebuffer.
+pub struct Display {
+    headless: bool,
+    show_overlay: bool,
+    overlay_position: OverlayPosition,
+    overlay_font_scale: f64,
+    zone_color: Scalar,
+}
+
+impl Display {
+    pub fn open(config: &DisplayConfig) -> opencv::Result<Self> {
+        if !config.headless {
+            highgui::named_window(WINDOW_NAME, highgui::WINDOW_AUTOSIZE)?;
+        }
+        let (b, g, r) = config.zone_color;
+        Ok(Display {
+            headless: config.headless,
+            show_overlay: config.show_overlay,
+            overlay_position: config.overlay_position,
+            overlay_font_scale: config.overlay_font_scale,
+            zone_color: Scalar::new(b as f64, g as f64, r as f64, 0.0),
+        })
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.headless
+    }
+
+    // Draws the zones and detected people onto the frame
+    pub fn annotate(&self, frame: &mut Mat, zones: &[Zone], boxes: &VectorOfRect) -> opencv::Result<()> {
+        draw_zones(frame, zones, self.zone_color)?;
+        draw_detections(frame, boxes)
+    }
+
+    // Yellow, drawn like the boxes also when headless for recordings
+    pub fn draw_counting_line(&self, frame: &mut Mat, line: ((i32, i32), (i32, i32))) -> opencv::Result<()> {
+        let ((x1, y1), (x2, y2)) = line;
+        imgproc::line(
+            frame,
+            Point::new(x1, y1),
+            Point::new(x2, y2),
+            Scalar::new(0.0, 255.0, 255.0, 0.0),
+            2,
+            imgproc::LINE_AA,
+            0,
+        )
+    }
+
+    // Top right corner, where zone names don't go.
+    // Moves to the top left when the count is shown top right.
+    pub fn draw_fps(&self, frame: &mut Mat, fps: f32) -> opencv::Result<()> {
+        if self.headless {
+            return Ok(());
+        }
+        let x = if self.show_overlay && self.overlay_position == OverlayPosition::TopRight {
+            OVERLAY_MARGIN
+        } else {
+            frame.cols() - 160
+        };
+        let origin = Point::new(x, 30);
+        imgproc::put_text(
+            frame,
+            &format!("FPS: {:.1}", fps),
+            origin,
+            imgproc::FONT_HERSHEY_SIMPLEX,
+            0.8,
+            Scalar::new(0.0, 255.0, 255.0, 0.0),
+            2,
+            imgproc::LINE_AA,
+            false,
+        )
+    }
+
+    // "Count: N" and the current time, each on a dark box so it stays
+    // readable over any background. Also drawn when headless, so snapshots
+    // and recordings carry it.
+    pub fn draw_overlay(&self, frame: &mut Mat, people_count: usize) -> opencv::Result<()> {
+        let timestamp = Local::now().format("%Y/%m/%d %H:%M:%S%.3f").to_string();
+        self.draw_overlay_with_time(frame, people_count, &timestamp)
+    }
+
+    // The same overlay with `time` in place of the clock, e.g. the position
+    // in a video file for `dry-run`
+    pub fn draw_overlay_with_time(&self, frame: &mut Mat, people_count: usize, time: &str) -> opencv::Result<()> {
+        if !self.show_overlay {
+            return Ok(());
+        }
+        let (top, right) = match self.overlay_position {
+            OverlayPosition::TopLeft => (true, false),
+            OverlayPosition::TopRight => (true, true),
+            OverlayPosition::BottomLeft => (false, false),
+            OverlayPosition::BottomRight => (false, true),
+        };
+        let scale = self.overlay_font_scale;
+        draw_label(frame, &format!("Count: {}", people_count), !top, right, scale)?;
+        draw_label(frame, time, top, right, scale)
+    }
+
+    // Shows the frame, returns false once 'q' has been pressed
+    pub fn show(&self, frame: &Mat) -> opencv::Result<bool> {
+        if self.headless {
+            return Ok(true);
+        }
+
+        highgui::imshow(WINDOW_NAME, frame)?;
+        Ok(highgui::wait_key(1)? != 'q' as i32)
+    }
+
+    pub fn close(&self) -> opencv::Result<()> {
+        if self.headless {
+            return Ok(());
+        }
+        highgui::destroy_all_windows()
+    }
+}
+
+// White text with a black shadow on a translucent dark box in a corner
+fn draw_label(frame: &mut Mat, text: &str, bottom: bool, right: bool, scale: f64) -> opencv::Result<()> {
+    let mut baseline = 0;
+    let size = imgproc::get_text_size(
+        text,
+        imgproc::FONT_HERSHEY_SIMPLEX,
+        scale,
+        OVERLAY_THICKNESS,
+        &mut baseline,
+    )?;
+    let width = size.width + 2 * OVERLAY_PADDING;
+    let height = size.height + baseline + 2 * OVERLAY_PADDING;
+    let x = if right {
+        frame.cols() - OVERLAY_MARGIN - width
+    } else {
+        OVERLAY_MARGIN
+    };
+    let y = if bottom {
+        frame.rows() - OVERLAY_MARGIN - height
+    } else {
+        OVERLAY_MARGIN
+    };
+    let background = Rect::new(x, y, width, height) & Rect::new(0, 0, frame.cols(), frame.rows());
+    if background.width <= 0 || background.height <= 0 {
+        return Ok(());
+    }
+
+    // The region shares the frame's pixels, blending into it darkens the frame
+    let mut region = Mat::roi(frame, background)?;
+    let dark = Mat::zeros(region.rows(), region.cols(), region.typ())?.to_mat()?;
+    let mut blended = Mat::default();
+    core::add_weighted(&region, 1.0 - OVERLAY_OPACITY, &dark, OVERLAY_OPACITY, 0.0, &mut blended, -1)?;
+    blended.copy_to(&mut region)?;
+
+    let origin = Point::new(x + OVERLAY_PADDING, y + OVERLAY_PADDING + size.height);
+    for (offset, color) in [
+        (Point::new(2, 2), Scalar::new(0.0, 0.0, 0.0, 0.0)),
+        (Point::new(0, 0), Scalar::new(255.0, 255.0, 255.0, 0.0)),
+    ] {
+        imgproc::put_text(
+            frame,
+            text,
+            origin + offset,
+            imgproc::FONT_HERSHEY_SIMPLEX,
+            scale,
+            color,
+            OVERLAY_THICKNESS,
+            imgproc::LINE_AA,
+            false,
+        )?;
+    }
+    Ok(())
+}
+
+fn draw_detections(frame: &mut Mat, boxes: &VectorOfRect) -> opencv::Result<()> {
+    for rect in boxes.iter() {
+        imgproc::rectangle(
+            frame,
+            rect,
+            Scalar::new(0.0, 255.0, 0.0, 0.0),
+            2,
+            imgproc::LINE_AA,
+            0,
+        )?;
+    }
+    Ok(())
+}
+
+fn draw_zones(frame: &mut Mat, zones: &[Zone], color: Scalar) -> opencv::Result<()> {
+    for zone in zones {
+        let rect = zone.rect();
+        imgproc::rectangle(frame, rect, color, 2, imgproc::LINE_AA, 0)?;
+        imgproc::put_text(
+            frame,
+            &zone.name,
+            Point::new(rect.x + 4, rect.y + 20),
+            imgproc::FONT_HERSHEY_SIMPLEX,
+            0.6,
+            color,
+            2,
+            imgproc::LINE_AA,
+            false,
+        )?;
+    }
+    Ok(())
+}
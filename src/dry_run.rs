@@ -0,0 +1,116 @@
+use opencv::{
+    core::{self, Mat, Size},
+    prelude::*,
+    videoio::{self, VideoCapture, VideoWriter},
+};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, DisplayConfig, DryRun};
+use crate::detector::create_detector;
+use crate::display::Display;
+use crate::error::AppError;
+use crate::orientation::Orientation;
+use crate::summary::Summary;
+use crate::zone;
+
+// Used when the container doesn't report a frame rate
+const FALLBACK_FPS: f64 = 25.0;
+
+// Runs the configured detector over a video file without touching MQTT, for
+// tuning detection parameters offline. Every frame is processed, none are
+// dropped as they would be from a live camera.
+pub fn run(config: &Config, dry_run: &DryRun) -> Result<(), AppError> {
+    let input = dry_run.input.to_string_lossy();
+    let mut video = VideoCapture::from_file(&input, videoio::CAP_ANY).map_err(AppError::Camera)?;
+    if !video.is_opened().map_err(AppError::Camera)? {
+        return Err(AppError::Camera(opencv::Error::new(
+            core::StsError,
+            format!("Unable to open video file {}", input),
+        )));
+    }
+    let fps = match video.get(videoio::CAP_PROP_FPS).map_err(AppError::Camera)? {
+        fps if fps > 0.0 => fps,
+        _ => FALLBACK_FPS,
+    };
+    log::info!("Dry run on {} at {:.1} fps", input, fps);
+
+    let mut detector = create_detector(config).map_err(AppError::Detection)?;
+    let orientation = Orientation::new(&config.camera);
+    // Only used for drawing, nothing is shown
+    let display = Display::open(&DisplayConfig {
+        headless: true,
+        ..config.display.clone()
+    })
+    .map_err(AppError::Display)?;
+
+    let mut writer: Option<VideoWriter> = None;
+    let mut summary = Summary::default();
+    let mut frame = Mat::default();
+    while video.read(&mut frame).map_err(AppError::Camera)? {
+        if frame.empty() {
+            break;
+        }
+        if let Some(orientation) = &orientation {
+            orientation.apply(&mut frame).map_err(AppError::Camera)?;
+        }
+
+        let started = Instant::now();
+        let boxes = zone::filter(&config.zones, &detector.detect(&frame).map_err(AppError::Detection)?);
+        summary.add(boxes.len(), started.elapsed());
+
+        let Some(output) = &dry_run.output else {
+            continue;
+        };
+        let writer = match &mut writer {
+            Some(writer) => writer,
+            None => writer.insert(open_writer(output, fps, &frame).map_err(AppError::Recording)?),
+        };
+        display
+            .annotate(&mut frame, &config.zones, &boxes)
+            .map_err(AppError::Display)?;
+        let position = video.get(videoio::CAP_PROP_POS_MSEC).map_err(AppError::Camera)?;
+        display
+            .draw_overlay_with_time(&mut frame, boxes.len(), &video_position(position))
+            .map_err(AppError::Display)?;
+        writer.write(&frame).map_err(AppError::Recording)?;
+    }
+
+    if let Some(mut writer) = writer {
+        writer.release().map_err(AppError::Recording)?;
+    }
+    summary.print();
+    Ok(())
+}
+
+fn open_writer(path: &Path, fps: f64, frame: &Mat) -> opencv::Result<VideoWriter> {
+    let path = path.to_string_lossy();
+    let writer = VideoWriter::new(
+        &path,
+        VideoWriter::fourcc('M', 'J', 'P', 'G')?,
+        fps,
+        Size::new(frame.cols(), frame.rows()),
+        true,
+    )?;
+    if !writer.is_opened()? {
+        return Err(opencv::Error::new(
+            core::StsError,
+            format!("Unable to open {} for writing", path),
+        ));
+    }
+    log::info!("Writing annotated video to {}", path);
+    Ok(writer)
+}
+
+// Shown in place of the clock, e.g. 00:01:23.456
+fn video_position(position_ms: f64) -> String {
+    let position = Duration::from_millis(position_ms.max(0.0) as u64);
+    let seconds = position.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        position.subsec_millis()
+    )
+}
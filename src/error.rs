@@ -0,0 +1,65 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::config::ConfigError;
+
+// Everything that stops the detector. Problems it can recover from, such as
+// a failed publish or a dropped broker connection, are logged where they
+// happen instead.
+pub enum AppError {
+    Config(ConfigError),
+    Camera(opencv::Error),
+    Detection(opencv::Error),
+    // Setting up the MQTT client, e.g. unreadable TLS certificates
+    Mqtt(ConfigError),
+    // The broker refused our username and password
+    MqttAuth(String),
+    Display(opencv::Error),
+    Recording(opencv::Error),
+    Database(rusqlite::Error),
+    Io { path: PathBuf, source: std::io::Error },
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Config(e) => write!(f, "failed to load configuration: {}", e),
+            AppError::Camera(e) => write!(f, "camera error: {}", e),
+            AppError::Detection(e) => write!(f, "detection failed: {}", e),
+            AppError::Mqtt(e) => write!(f, "failed to configure MQTT: {}", e),
+            AppError::MqttAuth(reason) => write!(f, "AUTH_FAILED: {}", reason),
+            AppError::Display(e) => write!(f, "preview window error: {}", e),
+            AppError::Recording(e) => write!(f, "recording failed: {}", e),
+            AppError::Database(e) => write!(f, "database error: {}", e),
+            AppError::Io { path, source } => write!(f, "unable to access {}: {}", path.display(), source),
+        }
+    }
+}
+
+// `main` returning an error prints it with Debug, keep that readable
+impl fmt::Debug for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for AppError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AppError::Config(e) | AppError::Mqtt(e) => Some(e),
+            AppError::Camera(e)
+            | AppError::Detection(e)
+            | AppError::Display(e)
+            | AppError::Recording(e) => Some(e),
+            AppError::Database(e) => Some(e),
+            AppError::Io { source, .. } => Some(source),
+            AppError::MqttAuth(_) => None,
+        }
+    }
+}
+
+impl From<ConfigError> for AppError {
+    fn from(e: ConfigError) -> Self {
+        AppError::Config(e)
+    }
+}
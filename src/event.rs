@@ -0,0 +1,372 @@
+use chrono::{SecondsFormat, Utc};
+use opencv::{core::Rect, types::VectorOfRect};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::config::PayloadFormat;
+use crate::tracker::TrackedPerson;
+
+// Payload published to MQTT after each detection pass
+#[derive(Debug, Clone, Serialize)]
+pub struct DetectionEvent {
+    pub timestamp: String,
+    // Debounced, see `debounce::Debouncer`
+    pub count: usize,
+    // People found in this very frame
+    pub raw_count: usize,
+    // False when the motion filter saw a static scene and the boxes were
+    // carried over from the last detection
+    pub motion_detected: bool,
+    // Camera the event was detected on, see `CameraSource::label`
+    pub source: String,
+    // Size of the frame the boxes refer to
+    pub frame: FrameSize,
+    pub bounding_boxes: Vec<BoundingBox>,
+    pub tracks: Vec<Track>,
+    // Distinct people tracked since start, see `CentroidTracker::unique_count`. Left
+    // out of events that weren't detected, e.g. the empty scene on shutdown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unique_count: Option<u64>,
+    // Time since the frame the boxes were found on was captured, includes
+    // detection and grows while the motion filter carries the boxes over
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detection_age_ms: Option<u64>,
+    // People per configured zone, left out when no zones are configured
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub zones: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FrameSize {
+    pub width: i32,
+    pub height: i32,
+}
+
+// Person bounding box in absolute pixel coordinates of the captured frame
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct BoundingBox {
+    pub x: i32,
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+}
+
+// A person with the id the tracker kept for them across frames
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Track {
+    pub id: u32,
+    pub bbox: BoundingBox,
+    pub age_frames: u32,
+}
+
+impl From<&TrackedPerson> for Track {
+    fn from(person: &TrackedPerson) -> Self {
+        Track {
+            id: person.id,
+            bbox: person.bbox.into(),
+            age_frames: person.age_frames,
+        }
+    }
+}
+
+impl From<Rect> for BoundingBox {
+    fn from(rect: Rect) -> Self {
+        BoundingBox {
+            x: rect.x,
+            y: rect.y,
+            w: rect.width,
+            h: rect.height,
+        }
+    }
+}
+
+// Payload published to the stats topic at a fixed interval
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsEvent {
+    pub timestamp: String,
+    pub frames_dropped: u64,
+    // Measured rates, frames read from the camera and detection passes
+    pub capture_fps: f32,
+    pub detection_fps: f32,
+    pub stages: StageTimings,
+    // Resident memory of the process, should stay flat on a long run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rss_mb: Option<f32>,
+}
+
+impl StatsEvent {
+    pub fn new(
+        frames_dropped: u64,
+        capture_fps: f32,
+        detection_fps: f32,
+        stages: StageTimings,
+        rss_bytes: Option<u64>,
+    ) -> Self {
+        StatsEvent {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            frames_dropped,
+            capture_fps: round_tenth(capture_fps),
+            detection_fps: round_tenth(detection_fps),
+            stages,
+            rss_mb: rss_bytes.map(|bytes| round_tenth(bytes as f32 / (1024.0 * 1024.0))),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+// Average milliseconds per stage over the last stats interval, see `timing`
+#[derive(Debug, Clone, Serialize)]
+pub struct StageTimings {
+    // Reading and rotating a frame, includes waiting for the camera
+    pub capture_ms: f32,
+    pub detection_ms: f32,
+    pub draw_ms: f32,
+    // Tracking, counting and handing the messages to the publisher
+    pub publish_ms: f32,
+    pub display_ms: f32,
+}
+
+impl StageTimings {
+    pub fn new(capture_ms: f32, detection_ms: f32, draw_ms: f32, publish_ms: f32, display_ms: f32) -> Self {
+        StageTimings {
+            capture_ms: round_tenth(capture_ms),
+            detection_ms: round_tenth(detection_ms),
+            draw_ms: round_tenth(draw_ms),
+            publish_ms: round_tenth(publish_ms),
+            display_ms: round_tenth(display_ms),
+        }
+    }
+}
+
+// One decimal is plenty and keeps the payload readable
+fn round_tenth(value: f32) -> f32 {
+    (value * 10.0).round() / 10.0
+}
+
+// Published to `<topic>/total` when several cameras run in one process, see
+// `multi_camera`
+#[derive(Debug, Clone, Serialize)]
+pub struct TotalEvent {
+    pub timestamp: String,
+    pub count: usize,
+    // Debounced count per camera device id
+    pub cameras: BTreeMap<String, usize>,
+}
+
+impl TotalEvent {
+    pub fn new(cameras: BTreeMap<String, usize>) -> Self {
+        TotalEvent {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            count: cameras.values().sum(),
+            cameras,
+        }
+    }
+
+    pub fn to_payload(&self, format: PayloadFormat) -> serde_json::Result<String> {
+        match format {
+            PayloadFormat::Plain => Ok(self.count.to_string()),
+            PayloadFormat::Json => serde_json::to_string(self),
+        }
+    }
+}
+
+// Published to the event topic when people enter or leave the scene, see
+// `transition::TransitionDetector`
+#[derive(Debug, Clone, Serialize)]
+pub struct TransitionEvent {
+    #[serde(rename = "type")]
+    pub kind: TransitionKind,
+    // Change in the people count, negative for `leave`
+    pub delta: i64,
+    pub count: usize,
+    pub timestamp: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionKind {
+    Enter,
+    Leave,
+}
+
+impl TransitionEvent {
+    pub fn new(kind: TransitionKind, delta: i64, count: usize) -> Self {
+        TransitionEvent {
+            kind,
+            delta,
+            count,
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+// Published to the line topic when a tracked person crosses the counting
+// line, see `line_crossing::LineCounter`
+#[derive(Debug, Clone, Serialize)]
+pub struct CrossingEvent {
+    #[serde(rename = "type")]
+    pub direction: CrossingDirection,
+    pub track_id: u32,
+    // Crossings since start or the last reset
+    pub count_in: u64,
+    pub count_out: u64,
+    pub timestamp: String,
+}
+
+// Published to the crossings topic after every crossing and reset
+#[derive(Debug, Clone, Serialize)]
+pub struct CrossingTotals {
+    pub entries: u64,
+    pub exits: u64,
+    // Entries minus exits, the people inside when counting started empty
+    pub net: i64,
+    pub timestamp: String,
+}
+
+impl CrossingTotals {
+    pub fn new(entries: u64, exits: u64) -> Self {
+        CrossingTotals {
+            entries,
+            exits,
+            net: entries as i64 - exits as i64,
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CrossingDirection {
+    In,
+    Out,
+}
+
+impl CrossingEvent {
+    pub fn new(direction: CrossingDirection, track_id: u32, count_in: u64, count_out: u64) -> Self {
+        CrossingEvent {
+            direction,
+            track_id,
+            count_in,
+            count_out,
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+impl DetectionEvent {
+    pub fn new(
+        boxes: &VectorOfRect,
+        count: usize,
+        frame: FrameSize,
+        tracks: &[TrackedPerson],
+        zones: BTreeMap<String, usize>,
+        source: String,
+        motion_detected: bool,
+    ) -> Self {
+        DetectionEvent {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            count,
+            raw_count: boxes.len(),
+            motion_detected,
+            source,
+            frame,
+            bounding_boxes: boxes.iter().map(BoundingBox::from).collect(),
+            tracks: tracks.iter().map(Track::from).collect(),
+            unique_count: None,
+            detection_age_ms: None,
+            zones,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+// Plain keeps the bare count older subscribers (e.g. Node-RED flows) expect
+pub fn build_payload(event: &DetectionEvent, format: PayloadFormat) -> serde_json::Result<String> {
+    match format {
+        PayloadFormat::Plain => Ok(event.count.to_string()),
+        PayloadFormat::Json => event.to_json(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn event(tracks: &[TrackedPerson], zones: BTreeMap<String, usize>) -> DetectionEvent {
+        let boxes = VectorOfRect::from_iter([Rect::new(10, 20, 64, 128)]);
+        let frame = FrameSize { width: 640, height: 480 };
+        DetectionEvent::new(&boxes, 1, frame, tracks, zones, "0".to_string(), true)
+    }
+
+    fn to_value(event: &DetectionEvent) -> Value {
+        serde_json::from_str(&build_payload(event, PayloadFormat::Json).expect("event serializes"))
+            .expect("payload is JSON")
+    }
+
+    #[test]
+    fn json_payload_keeps_its_field_names() {
+        let mut payload = to_value(&event(&[], BTreeMap::new()));
+        assert!(payload["timestamp"].is_string());
+        payload["timestamp"] = json!("");
+        assert_eq!(
+            payload,
+            json!({
+                "timestamp": "",
+                "count": 1,
+                "raw_count": 1,
+                "motion_detected": true,
+                "source": "0",
+                "frame": {"width": 640, "height": 480},
+                "bounding_boxes": [{"x": 10, "y": 20, "w": 64, "h": 128}],
+                "tracks": [],
+            })
+        );
+    }
+
+    #[test]
+    fn json_payload_includes_the_optional_fields_when_set() {
+        let person = TrackedPerson {
+            id: 7,
+            bbox: Rect::new(10, 20, 64, 128),
+            age_frames: 12,
+            missed_frames: 0,
+        };
+        let zones = BTreeMap::from([("door".to_string(), 1)]);
+        let mut event = event(&[person], zones);
+        event.unique_count = Some(9);
+        event.detection_age_ms = Some(250);
+
+        let payload = to_value(&event);
+        assert_eq!(
+            payload["tracks"],
+            json!([{"id": 7, "bbox": {"x": 10, "y": 20, "w": 64, "h": 128}, "age_frames": 12}])
+        );
+        assert_eq!(payload["zones"], json!({"door": 1}));
+        assert_eq!(payload["unique_count"], json!(9));
+        assert_eq!(payload["detection_age_ms"], json!(250));
+    }
+
+    #[test]
+    fn plain_payload_is_the_bare_count() {
+        assert_eq!(build_payload(&event(&[], BTreeMap::new()), PayloadFormat::Plain).unwrap(), "1");
+    }
+}
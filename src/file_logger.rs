@@ -0,0 +1,78 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::LogFileConfig;
+use crate::event::DetectionEvent;
+
+// Appends one JSON line per detection event. Once the file reaches
+// `max_size_mb` it is renamed to `<path>.1`, older files shift up to
+// `<path>.<max_files>` and the oldest beyond that is deleted.
+pub struct FileLogger {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: u32,
+    writer: BufWriter<File>,
+    written: u64,
+}
+
+impl FileLogger {
+    pub fn open(config: &LogFileConfig) -> io::Result<Self> {
+        let (writer, written) = append(&config.path)?;
+        Ok(FileLogger {
+            path: config.path.clone(),
+            max_bytes: config.max_size_mb * 1024 * 1024,
+            max_files: config.max_files,
+            writer,
+            written,
+        })
+    }
+
+    pub fn write(&mut self, event: &DetectionEvent) -> io::Result<()> {
+        let line = event.to_json()?;
+        if self.written > 0 && self.written + line.len() as u64 + 1 > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.writer, "{}", line)?;
+        // Complete lines only, so `tail -f` and a crash never see half an event
+        self.writer.flush()?;
+        self.written += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let oldest = numbered(&self.path, self.max_files);
+            if oldest.exists() {
+                fs::remove_file(&oldest)?;
+            }
+            for n in (1..self.max_files).rev() {
+                let from = numbered(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, numbered(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        }
+        let (writer, written) = append(&self.path)?;
+        self.writer = writer;
+        self.written = written;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> io::Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let written = file.metadata()?.len();
+    Ok((BufWriter::new(file), written))
+}
+
+// `detections.jsonl` -> `detections.jsonl.<n>`
+fn numbered(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
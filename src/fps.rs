@@ -0,0 +1,40 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+// Frames the rate is measured over, enough that one slow frame doesn't make
+// the value jump around
+const WINDOW: usize = 30;
+
+// Rolling frames per second over the last `WINDOW` frames
+#[derive(Debug, Default)]
+pub struct FpsCounter {
+    frames: VecDeque<Instant>,
+}
+
+impl FpsCounter {
+    pub fn new() -> Self {
+        FpsCounter::default()
+    }
+
+    // Call once per frame, returns the current estimate
+    pub fn tick(&mut self) -> f32 {
+        if self.frames.len() == WINDOW {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(Instant::now());
+        self.fps()
+    }
+
+    // 0 until two frames have been seen
+    pub fn fps(&self) -> f32 {
+        let (Some(first), Some(last)) = (self.frames.front(), self.frames.back()) else {
+            return 0.0;
+        };
+        let elapsed = last.duration_since(*first).as_secs_f32();
+        if elapsed > 0.0 {
+            (self.frames.len() - 1) as f32 / elapsed
+        } else {
+            0.0
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use chrono::Local;
+use opencv::{
+    core::{self, Mat, Rect, Scalar, Size, Vector},
+    imgcodecs, imgproc,
+    prelude::*,
+    types::VectorOfRect,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::config::HeatmapConfig;
+
+// Share of the heatmap in the preview when it is shown
+const PREVIEW_ALPHA: f64 = 0.3;
+
+// Where people were over time: every pixel counts the detection passes a
+// person's box covered it. Saved as a colored PNG every `save_interval`.
+pub struct Heatmap {
+    dir: PathBuf,
+    save_interval: Duration,
+    show: bool,
+    // CV_32F, created at the size of the first frame
+    accumulator: Option<Mat>,
+    // Reused so a pass doesn't allocate frame-sized Mats: the boxes of one
+    // pass, the new sum swapped with `accumulator`, and the accumulator
+    // scaled to 8 bits and colored. `colored` is only kept up to date for
+    // the preview when shown.
+    covered: Mat,
+    sum: Mat,
+    scaled: Mat,
+    colored: Mat,
+    last_saved: Instant,
+}
+
+impl Heatmap {
+    pub fn new(config: &HeatmapConfig) -> Self {
+        Heatmap {
+            dir: config.dir.clone(),
+            save_interval: Duration::from_secs(config.save_interval_secs),
+            show: config.show,
+            accumulator: None,
+            covered: Mat::default(),
+            sum: Mat::default(),
+            scaled: Mat::default(),
+            colored: Mat::default(),
+            last_saved: Instant::now(),
+        }
+    }
+
+    // Adds one detection pass on a frame of `size`. A camera that comes back
+    // at another resolution starts a new heatmap.
+    pub fn add(&mut self, size: Size, boxes: &VectorOfRect) -> opencv::Result<()> {
+        let accumulator = match &mut self.accumulator {
+            Some(accumulator) if accumulator.size()? == size => accumulator,
+            _ => self
+                .accumulator
+                .insert(Mat::zeros(size.height, size.width, core::CV_32F)?.to_mat()?),
+        };
+
+        // 1 wherever at least one box covers the pixel, overlapping boxes count once
+        if self.covered.size()? == size {
+            self.covered.set_to(&Scalar::all(0.0), &core::no_array())?;
+        } else {
+            self.covered = Mat::zeros(size.height, size.width, core::CV_32F)?.to_mat()?;
+        }
+        let frame = Rect::new(0, 0, size.width, size.height);
+        for person in boxes.iter() {
+            imgproc::rectangle(
+                &mut self.covered,
+                person & frame,
+                Scalar::all(1.0),
+                imgproc::FILLED,
+                imgproc::LINE_8,
+                0,
+            )?;
+        }
+        core::add(&*accumulator, &self.covered, &mut self.sum, &core::no_array(), -1)?;
+        std::mem::swap(accumulator, &mut self.sum);
+
+        if self.show {
+            colorize(accumulator, &mut self.scaled, &mut self.colored)?;
+        }
+        Ok(())
+    }
+
+    pub fn save_due(&self) -> bool {
+        self.accumulator.is_some() && self.last_saved.elapsed() >= self.save_interval
+    }
+
+    // Writes `<dir>/heatmap_<timestamp>.png`, the accumulation goes on
+    pub fn save(&mut self) -> opencv::Result<Option<PathBuf>> {
+        self.last_saved = Instant::now();
+        let Some(accumulator) = &self.accumulator else {
+            return Ok(None);
+        };
+        let path = self.dir.join(format!(
+            "heatmap_{}.png",
+            Local::now().format("%Y%m%d_%H%M%S")
+        ));
+        colorize(accumulator, &mut self.scaled, &mut self.colored)?;
+        write_png(&self.dir, &path, &self.colored)?;
+        Ok(Some(path))
+    }
+
+    // Blends the heatmap into the preview frame when `show` is set
+    pub fn blend(&self, frame: &mut Mat) -> opencv::Result<()> {
+        let colored = &self.colored;
+        // Empty before the first pass
+        if !self.show || colored.size()? != frame.size()? || frame.typ() != colored.typ() {
+            return Ok(());
+        }
+        let mut blended = Mat::default();
+        core::add_weighted(
+            &*frame,
+            1.0 - PREVIEW_ALPHA,
+            colored,
+            PREVIEW_ALPHA,
+            0.0,
+            &mut blended,
+            -1,
+        )?;
+        *frame = blended;
+        Ok(())
+    }
+}
+
+// The busiest pixel becomes red, pixels nobody covered dark blue
+fn colorize(accumulator: &Mat, scaled: &mut Mat, colored: &mut Mat) -> opencv::Result<()> {
+    core::normalize(
+        accumulator,
+        scaled,
+        0.0,
+        255.0,
+        core::NORM_MINMAX,
+        core::CV_8U,
+        &core::no_array(),
+    )?;
+    imgproc::apply_color_map(&*scaled, colored, imgproc::COLORMAP_JET)?;
+    Ok(())
+}
+
+fn write_png(dir: &Path, path: &Path, image: &Mat) -> opencv::Result<()> {
+    fs::create_dir_all(dir).map_err(|e| {
+        opencv::Error::new(
+            opencv::core::StsError,
+            format!("Unable to create {}: {}", dir.display(), e),
+        )
+    })?;
+    if !imgcodecs::imwrite(&path.to_string_lossy(), image, &Vector::new())? {
+        return Err(opencv::Error::new(
+            opencv::core::StsError,
+            format!("Unable to write {}", path.display()),
+        ));
+    }
+    Ok(())
+}
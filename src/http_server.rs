@@ -0,0 +1,142 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use serde_json::json;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::watch;
+
+use crate::event::DetectionEvent;
+
+// Requests per second across all clients, a polling loop gone wrong
+// shouldn't be able to slow down detection
+const RATE_LIMIT: f64 = 100.0;
+
+// Serves the latest detection for clients that poll instead of subscribing:
+// `GET /state` returns the last `DetectionEvent`, `GET /count` just its count
+// and timestamp, `GET /health` the uptime.
+// Like the metrics server, a failure is logged and never stops detection.
+pub fn spawn(addr: SocketAddr, state: watch::Receiver<DetectionEvent>) {
+    let shared = Arc::new(Shared {
+        state,
+        started: Instant::now(),
+        limiter: Mutex::new(TokenBucket::new(RATE_LIMIT)),
+    });
+
+    tokio::spawn(async move {
+        let server = match Server::try_bind(&addr) {
+            Ok(server) => server,
+            Err(e) => {
+                log::error!("Unable to start HTTP server on {}: {}", addr, e);
+                return;
+            }
+        };
+        log::info!("Serving detection state on http://{}/state", addr);
+        let service = make_service_fn(move |_| {
+            let shared = Arc::clone(&shared);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let shared = Arc::clone(&shared);
+                    async move { Ok::<_, Infallible>(shared.handle(request)) }
+                }))
+            }
+        });
+        if let Err(e) = server.serve(service).await {
+            log::error!("HTTP server failed: {}", e);
+        }
+    });
+}
+
+struct Shared {
+    state: watch::Receiver<DetectionEvent>,
+    started: Instant,
+    limiter: Mutex<TokenBucket>,
+}
+
+impl Shared {
+    fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let allowed = match self.limiter.lock() {
+            Ok(mut limiter) => limiter.try_take(),
+            Err(_) => true,
+        };
+        if !allowed {
+            return text(StatusCode::TOO_MANY_REQUESTS, "too many requests\n");
+        }
+
+        if request.method() != Method::GET {
+            return text(StatusCode::NOT_FOUND, "not found\n");
+        }
+        match request.uri().path() {
+            "/state" => match serde_json::to_string(&*self.state.borrow()) {
+                Ok(body) => json_response(body),
+                Err(e) => {
+                    log::warn!("Failed to serialize detection state: {}", e);
+                    text(StatusCode::INTERNAL_SERVER_ERROR, "failed to serialize state\n")
+                }
+            },
+            "/count" => {
+                let state = self.state.borrow();
+                json_response(
+                    json!({
+                        "count": state.count,
+                        "timestamp": state.timestamp,
+                    })
+                    .to_string(),
+                )
+            }
+            "/health" => json_response(
+                json!({
+                    "status": "ok",
+                    "uptime_secs": self.started.elapsed().as_secs(),
+                })
+                .to_string(),
+            ),
+            _ => text(StatusCode::NOT_FOUND, "not found\n"),
+        }
+    }
+}
+
+fn json_response(body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+fn text(status: StatusCode, body: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
+
+// Refills `rate` tokens per second up to a burst of `rate`, each request takes one
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
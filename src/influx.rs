@@ -0,0 +1,225 @@
+use chrono::Utc;
+use hyper::client::HttpConnector;
+use hyper::{header, Body, Client, Method, Request, StatusCode, Uri};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+use crate::backoff::Backoff;
+use crate::config::InfluxConfig;
+
+// Points sent in one request, also the most kept while the server is down
+const MAX_POINTS: usize = 100;
+// Points waiting for the writer task
+const QUEUE_CAPACITY: usize = 256;
+// A hanging server counts as a network error
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Waits between failed writes, a 429 with Retry-After waits that long instead
+const RETRY_INITIAL: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+// Writes one point per detection pass to InfluxDB v2 in line protocol, e.g.
+// `person_detector,camera=cam0 count=3i,fps=12.3 1705314600123000000`.
+// Points are batched on a task of their own, the detection loop only pays
+// for formatting the line. Failures are logged and never stop detection.
+pub struct InfluxWriter {
+    measurement: String,
+    // Tag set including the leading comma
+    tags: String,
+    points: mpsc::Sender<String>,
+    writer: JoinHandle<()>,
+}
+
+impl InfluxWriter {
+    // `url` was checked by `Config::validate`
+    pub fn spawn(config: &InfluxConfig, url: &str, camera: &str) -> Result<Self, hyper::http::Error> {
+        let write_uri = Uri::try_from(format!(
+            "{}/api/v2/write?org={}&bucket={}&precision=ns",
+            url.trim_end_matches('/'),
+            query_escape(&config.org),
+            query_escape(&config.bucket)
+        ))?;
+        let (points, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let batcher = Batcher {
+            client: Client::new(),
+            uri: write_uri,
+            token: config.token.clone(),
+            points: Vec::with_capacity(MAX_POINTS),
+            backoff: Backoff::new(RETRY_INITIAL, RETRY_MAX),
+            retry_at: None,
+            unauthorized: false,
+        };
+        let flush_interval = Duration::from_millis(config.flush_interval_ms);
+        Ok(InfluxWriter {
+            measurement: escape(&config.measurement, ", "),
+            tags: format!(",camera={}", escape(camera, ", =")),
+            points,
+            writer: tokio::spawn(batcher.run(receiver, flush_interval)),
+        })
+    }
+
+    // Never blocks, the point is dropped when the writer has fallen that far behind
+    pub fn write(&self, count: usize, fps: f32) {
+        let timestamp = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        let point = format!(
+            "{}{} count={}i,fps={:.1} {}",
+            self.measurement, self.tags, count, fps, timestamp
+        );
+        if self.points.try_send(point).is_err() {
+            log::warn!("InfluxDB writer is behind, dropping point");
+        }
+    }
+
+    // Sends what is still buffered, one attempt
+    pub async fn close(self) {
+        drop(self.points);
+        if let Err(e) = self.writer.await {
+            log::error!("InfluxDB writer failed: {}", e);
+        }
+    }
+}
+
+struct Batcher {
+    client: Client<HttpConnector>,
+    uri: Uri,
+    token: Option<String>,
+    points: Vec<String>,
+    backoff: Backoff,
+    // No writes before this after a failure
+    retry_at: Option<Instant>,
+    // Logged once instead of on every batch until the token works again
+    unauthorized: bool,
+}
+
+impl Batcher {
+    async fn run(mut self, mut points: mpsc::Receiver<String>, flush_interval: Duration) {
+        let mut interval = time::interval(flush_interval);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                point = points.recv() => match point {
+                    Some(point) => {
+                        if self.points.len() == MAX_POINTS {
+                            // Only happens while the server is failing, the newest points matter most
+                            self.points.remove(0);
+                        }
+                        self.points.push(point);
+                        if self.points.len() < MAX_POINTS {
+                            continue;
+                        }
+                    }
+                    None => {
+                        self.retry_at = None;
+                        self.flush().await;
+                        return;
+                    }
+                },
+                _ = interval.tick() => {}
+            }
+            self.flush().await;
+        }
+    }
+
+    async fn flush(&mut self) {
+        if self.points.is_empty() || self.retry_at.is_some_and(|at| Instant::now() < at) {
+            return;
+        }
+        let body = self.points.join("\n");
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8");
+        if let Some(token) = &self.token {
+            request = request.header(header::AUTHORIZATION, format!("Token {}", token));
+        }
+        let request = match request.body(Body::from(body)) {
+            Ok(request) => request,
+            Err(e) => {
+                log::warn!("Failed to build InfluxDB request: {}", e);
+                self.points.clear();
+                return;
+            }
+        };
+
+        let response = match time::timeout(REQUEST_TIMEOUT, self.client.request(request)).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                self.retry_later(None, &format!("unable to reach InfluxDB: {}", e));
+                return;
+            }
+            Err(_) => {
+                self.retry_later(None, "InfluxDB didn't answer in time");
+                return;
+            }
+        };
+        match response.status() {
+            status if status.is_success() => {
+                if self.unauthorized {
+                    log::info!("InfluxDB accepts the token again");
+                }
+                self.unauthorized = false;
+                self.backoff.reset();
+                self.retry_at = None;
+                self.points.clear();
+            }
+            // Retrying won't help until the token is fixed, the batch is dropped
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                if !self.unauthorized {
+                    log::error!(
+                        "InfluxDB rejected the token ({}), check influxdb.token and its write permission on the bucket",
+                        response.status()
+                    );
+                }
+                self.unauthorized = true;
+                self.points.clear();
+            }
+            StatusCode::TOO_MANY_REQUESTS => {
+                let retry_after = response
+                    .headers()
+                    .get(header::RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.trim().parse().ok())
+                    .map(Duration::from_secs);
+                self.retry_later(retry_after, "InfluxDB is rate limiting writes");
+            }
+            status if status.is_server_error() => {
+                self.retry_later(None, &format!("InfluxDB failed with {}", status));
+            }
+            // E.g. 400 for a point it can't parse, sending it again gets the same answer
+            status => {
+                log::warn!("InfluxDB refused {} points with {}", self.points.len(), status);
+                self.points.clear();
+            }
+        }
+    }
+
+    // Keeps the points for the next attempt
+    fn retry_later(&mut self, delay: Option<Duration>, reason: &str) {
+        let delay = delay.unwrap_or_else(|| self.backoff.next_delay());
+        log::warn!("{}, retrying in {:?}", reason, delay);
+        self.retry_at = Some(Instant::now() + delay);
+    }
+}
+
+// Line protocol escapes these with a backslash in measurements and tags
+fn escape(value: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn query_escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
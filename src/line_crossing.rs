@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+
+use crate::config::LineCrossingConfig;
+use crate::event::{CrossingDirection, CrossingEvent, CrossingTotals};
+use crate::tracker::TrackedPerson;
+
+// Side of the line a track was last seen clearly on, looking from `from` to
+// `to` in image coordinates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Left,
+    Right,
+}
+
+struct TrackSide {
+    side: Side,
+    // Detection pass the track was last seen on
+    seen: u64,
+}
+
+// Counts tracked people crossing a line. A crossing only counts once the
+// centroid is `hysteresis` pixels past the line after having been that far
+// on the other side, so someone hovering on the line doesn't count at all.
+pub struct LineCounter {
+    from: (f32, f32),
+    // Unit vector from `from` to `to`
+    direction: (f32, f32),
+    hysteresis: f32,
+    invert: bool,
+    // Sides are kept a while after a track is lost, it may come back
+    max_missed_passes: u64,
+    sides: HashMap<u32, TrackSide>,
+    pass: u64,
+    entries: u64,
+    exits: u64,
+}
+
+impl LineCounter {
+    pub fn new(config: &LineCrossingConfig, max_missed_frames: u32) -> Self {
+        let (from, to) = config.line();
+        let (dx, dy) = ((to.0 - from.0) as f32, (to.1 - from.1) as f32);
+        // Validation rules out a line of length 0
+        let length = (dx * dx + dy * dy).sqrt();
+        LineCounter {
+            from: (from.0 as f32, from.1 as f32),
+            direction: (dx / length, dy / length),
+            hysteresis: config.hysteresis,
+            invert: config.invert,
+            max_missed_passes: u64::from(max_missed_frames) + 1,
+            sides: HashMap::new(),
+            pass: 0,
+            entries: 0,
+            exits: 0,
+        }
+    }
+
+    // Feeds the tracks of one detection pass, returns the crossings they made
+    pub fn update(&mut self, tracks: &[TrackedPerson]) -> Vec<CrossingEvent> {
+        self.pass += 1;
+        let mut crossings = Vec::new();
+        for track in tracks {
+            let Some(side) = self.side(track) else {
+                // On the line, only refresh when the track was last seen
+                if let Some(known) = self.sides.get_mut(&track.id) {
+                    known.seen = self.pass;
+                }
+                continue;
+            };
+            let previous = self.sides.insert(track.id, TrackSide { side, seen: self.pass });
+            let Some(previous) = previous.filter(|previous| previous.side != side) else {
+                continue;
+            };
+            // Left to right is "in" unless inverted
+            let direction = match (previous.side, self.invert) {
+                (Side::Left, false) | (Side::Right, true) => CrossingDirection::In,
+                _ => CrossingDirection::Out,
+            };
+            match direction {
+                CrossingDirection::In => self.entries += 1,
+                CrossingDirection::Out => self.exits += 1,
+            }
+            crossings.push(CrossingEvent::new(direction, track.id, self.entries, self.exits));
+        }
+
+        let (pass, max_missed_passes) = (self.pass, self.max_missed_passes);
+        self.sides.retain(|_, known| pass - known.seen <= max_missed_passes);
+        crossings
+    }
+
+    pub fn totals(&self) -> CrossingTotals {
+        CrossingTotals::new(self.entries, self.exits)
+    }
+
+    // Starts counting from 0 again, people keep the side they are on
+    pub fn reset(&mut self) {
+        self.entries = 0;
+        self.exits = 0;
+    }
+
+    // None while the centroid is within `hysteresis` of the line
+    fn side(&self, track: &TrackedPerson) -> Option<Side> {
+        let bbox = &track.bbox;
+        let x = bbox.x as f32 + bbox.width as f32 / 2.0 - self.from.0;
+        let y = bbox.y as f32 + bbox.height as f32 / 2.0 - self.from.1;
+        // Signed distance, positive on the right with y pointing down
+        let distance = self.direction.0 * y - self.direction.1 * x;
+        // Right on the line counts as neither side, even without hysteresis
+        if distance.abs() < self.hysteresis.max(f32::EPSILON) {
+            None
+        } else if distance > 0.0 {
+            Some(Side::Right)
+        } else {
+            Some(Side::Left)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LineOrientation;
+    use opencv::core::Rect;
+
+    // Horizontal line at y = 100, downwards is "in"
+    fn config() -> LineCrossingConfig {
+        LineCrossingConfig {
+            enabled: true,
+            orientation: LineOrientation::Horizontal,
+            position: 100,
+            from: None,
+            to: None,
+            hysteresis: 10.0,
+            invert: false,
+        }
+    }
+
+    // A box centred on (x, y)
+    fn person(id: u32, x: i32, y: i32) -> TrackedPerson {
+        TrackedPerson {
+            id,
+            bbox: Rect::new(x - 10, y - 20, 20, 40),
+            age_frames: 1,
+            missed_frames: 0,
+        }
+    }
+
+    // Feeds one track through `ys`, returns the directions of its crossings
+    fn walk(counter: &mut LineCounter, ys: &[i32]) -> Vec<CrossingDirection> {
+        ys.iter()
+            .flat_map(|&y| counter.update(&[person(1, 50, y)]))
+            .map(|event| event.direction)
+            .collect()
+    }
+
+    #[test]
+    fn crossing_down_is_in_and_up_is_out() {
+        let mut counter = LineCounter::new(&config(), 2);
+        assert_eq!(walk(&mut counter, &[60, 90, 110, 140]), vec![CrossingDirection::In]);
+        assert_eq!(walk(&mut counter, &[110, 90, 60]), vec![CrossingDirection::Out]);
+        let totals = counter.totals();
+        assert_eq!((totals.entries, totals.exits, totals.net), (1, 1, 0));
+    }
+
+    #[test]
+    fn vertical_line_counts_rightwards_as_in() {
+        let config = LineCrossingConfig {
+            orientation: LineOrientation::Vertical,
+            ..config()
+        };
+        let mut counter = LineCounter::new(&config, 2);
+        let events: Vec<_> = [60, 140, 60]
+            .iter()
+            .flat_map(|&x| counter.update(&[person(1, x, 50)]))
+            .map(|event| event.direction)
+            .collect();
+        assert_eq!(events, vec![CrossingDirection::In, CrossingDirection::Out]);
+    }
+
+    #[test]
+    fn diagonal_line_counts_left_to_right_as_in() {
+        // Looking from the top left corner to (100, 100), the bottom left
+        // half of the frame is on the right
+        let config = LineCrossingConfig {
+            from: Some((0, 0)),
+            to: Some((100, 100)),
+            ..config()
+        };
+        let mut counter = LineCounter::new(&config, 2);
+        assert!(counter.update(&[person(1, 80, 20)]).is_empty());
+        let events = counter.update(&[person(1, 20, 80)]);
+        assert_eq!(events[0].direction, CrossingDirection::In);
+        let events = counter.update(&[person(1, 80, 20)]);
+        assert_eq!(events[0].direction, CrossingDirection::Out);
+    }
+
+    #[test]
+    fn hovering_within_hysteresis_does_not_count() {
+        let mut counter = LineCounter::new(&config(), 2);
+        assert!(walk(&mut counter, &[60, 92, 108, 95, 105, 91, 109]).is_empty());
+        assert_eq!(counter.totals().entries, 0);
+        // Only clearly past the line on the other side it counts
+        assert_eq!(walk(&mut counter, &[111]), vec![CrossingDirection::In]);
+    }
+
+    #[test]
+    fn invert_swaps_the_directions() {
+        let config = LineCrossingConfig {
+            invert: true,
+            ..config()
+        };
+        let mut counter = LineCounter::new(&config, 2);
+        assert_eq!(walk(&mut counter, &[60, 140]), vec![CrossingDirection::Out]);
+        assert_eq!(walk(&mut counter, &[60]), vec![CrossingDirection::In]);
+    }
+
+    #[test]
+    fn track_missing_up_to_max_missed_still_counts() {
+        let mut counter = LineCounter::new(&config(), 2);
+        counter.update(&[person(1, 50, 60)]);
+        for _ in 0..2 {
+            assert!(counter.update(&[]).is_empty());
+        }
+        let events = counter.update(&[person(1, 50, 140)]);
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].track_id, events[0].direction), (1, CrossingDirection::In));
+    }
+
+    #[test]
+    fn track_missing_for_long_is_forgotten() {
+        let mut counter = LineCounter::new(&config(), 2);
+        counter.update(&[person(1, 50, 60)]);
+        // Longer than the tracker would have kept the id
+        for _ in 0..4 {
+            counter.update(&[]);
+        }
+        assert!(counter.update(&[person(1, 50, 140)]).is_empty());
+        assert_eq!(counter.totals().entries, 0);
+    }
+
+    #[test]
+    fn reset_zeroes_the_totals_but_keeps_the_sides() {
+        let mut counter = LineCounter::new(&config(), 2);
+        assert_eq!(walk(&mut counter, &[60, 140]), vec![CrossingDirection::In]);
+        counter.reset();
+        let totals = counter.totals();
+        assert_eq!((totals.entries, totals.exits, totals.net), (0, 0, 0));
+
+        // Still known to be below the line, so going back up is an exit
+        let events = counter.update(&[person(1, 50, 60)]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].direction, CrossingDirection::Out);
+        assert_eq!((events[0].count_in, events[0].count_out), (0, 1));
+    }
+}
@@ -1,160 +1,736 @@
-use opencv::{
-    core::{self, Size, Mat},
-    highgui, imgproc, prelude::*, videoio,
-    objdetect::HOGDescriptor,
-    types::VectorOfRect,
-};
-use std::env;
+use opencv::{prelude::*, types::VectorOfRect};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::task;
-use tokio::time::{self, Duration};
-use rumqttc::{MqttOptions, AsyncClient, QoS};
-use chrono::{Local};
-
-fn get_timestamp() -> String {
-    let now = Local::now();
-    now.format("[%Y/%m/%d/%H/%M/%S%.3f]").to_string()  // Add milliseconds
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::{self, Duration, Instant};
+
+mod alert;
+mod backoff;
+mod benchmark;
+mod camera;
+mod commands;
+mod config;
+mod db;
+mod debounce;
+mod detection_worker;
+mod detector;
+mod discovery;
+mod display;
+mod dry_run;
+mod error;
+mod event;
+mod file_logger;
+mod fps;
+mod heatmap;
+mod http_server;
+mod influx;
+mod line_crossing;
+mod metrics;
+mod motion;
+mod mqtt;
+mod multi_camera;
+mod orientation;
+mod person_topics;
+mod preprocessing;
+mod publisher;
+mod recording;
+#[cfg(feature = "s3-upload")]
+mod s3;
+mod shutdown;
+mod snapshot;
+mod summary;
+mod timing;
+mod tls;
+mod tracker;
+mod transition;
+mod ws_server;
+mod zone;
+
+use alert::AlertMonitor;
+use camera::{Camera, CaptureStats, CapturedFrame};
+use commands::{Command, CommandTopic, Controls};
+use clap::Parser;
+use config::{AlertPayload, Cli, Config, ConfigError};
+use debounce::Debouncer;
+use detection_worker::DetectionWorker;
+use display::Display;
+use error::AppError;
+use event::{build_payload, BoundingBox, DetectionEvent, FrameSize, StatsEvent};
+use file_logger::FileLogger;
+use fps::FpsCounter;
+use heatmap::Heatmap;
+use influx::InfluxWriter;
+use line_crossing::LineCounter;
+use motion::MotionFilter;
+use person_topics::PersonTopics;
+use publisher::Publisher;
+use snapshot::{SnapshotPublisher, SnapshotSaver};
+use summary::Summary;
+use timing::StageTimer;
+use recording::FrameRecorder;
+use tracker::CentroidTracker;
+use transition::TransitionDetector;
+
+// How long shutdown waits for the broker before exiting anyway
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+// How often capture statistics are published
+const STATS_INTERVAL: Duration = Duration::from_secs(30);
+// How often the measured capture and detection rates are logged
+const FPS_LOG_INTERVAL: Duration = Duration::from_secs(1);
+// Events a WebSocket client may fall behind before it skips some
+const WS_EVENT_CAPACITY: usize = 16;
+
+fn publish_stats(
+    publisher: &Publisher,
+    topic: &str,
+    capture: &CaptureStats,
+    detection_fps: f32,
+    stage_timer: &mut StageTimer,
+) {
+    let frames_dropped = capture.frames_dropped.load(Ordering::Relaxed);
+    let stages = stage_timer.take(capture.take_read_ms());
+    log::debug!("Stage timings: {:?}", stages);
+    let rss_bytes = metrics::resident_memory_bytes();
+    if let Some(bytes) = rss_bytes {
+        metrics::RESIDENT_MEMORY_BYTES.set(bytes as i64);
+    }
+    let stats = match StatsEvent::new(frames_dropped, capture.fps(), detection_fps, stages, rss_bytes).to_json() {
+        Ok(json) => json,
+        Err(e) => {
+            log::warn!("Failed to serialize stats: {}", e);
+            return;
+        }
+    };
+    publisher.send(topic, stats, false);
 }
 
-fn colored_log(message: &str, color_code: &str) -> String {
-    format!("{}{}{}", color_code, message, "\x1b[0m")
+// Totals are state rather than events, retained like the count
+fn publish_crossings(publisher: &Publisher, topic: &str, line_counter: &LineCounter, retain: bool) {
+    match line_counter.totals().to_json() {
+        Ok(json) => publisher.send(topic, json, retain),
+        Err(e) => log::warn!("Failed to serialize line crossing totals: {}", e),
+    }
+}
+
+fn save_heatmap(heatmap: &mut Heatmap) {
+    match heatmap.save() {
+        Ok(Some(path)) => log::info!("Saved heatmap {}", path.display()),
+        Ok(None) => {}
+        Err(e) => log::warn!("Failed to save heatmap: {}", e),
+    }
+}
+
+// Waits forever when the command topic is disabled
+async fn next_command(commands: &mut Option<mpsc::Receiver<Command>>) -> Option<Command> {
+    match commands {
+        Some(commands) => commands.recv().await,
+        None => std::future::pending().await,
+    }
 }
 
 #[tokio::main]
-async fn main() -> opencv::Result<()> {
-    // Default values
-    let default_camera_index = 2;
-    let default_camera_frame_width = 1280.0;
-    let default_camera_frame_height = 720.0;
-    let default_broker_ip = "192.168.1.78".to_string();
-    let default_broker_ip_port = 1883;
-
-    // Collect command-line arguments
-    let args: Vec<String> = env::args().collect();
-    let camera_index = if args.len() > 1 {
-        args[1].parse().unwrap_or(default_camera_index)
-    } else {
-        default_camera_index
-    };
+async fn main() -> Result<(), AppError> {
+    let cli = Cli::parse();
+    let mut logger = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(cli.log_filter()));
+    // Colors are already left out when stderr isn't a terminal, e.g. under journald
+    if cli.color_disabled() {
+        logger.write_style(env_logger::WriteStyle::Never);
+    }
+    logger.init();
+
+    let config = Config::from_cli(cli)?;
+    if let Some(dry_run) = &config.dry_run {
+        return dry_run::run(&config, dry_run);
+    }
+    if let Some(frames) = config.benchmark {
+        return benchmark::run(&config, frames).await;
+    }
+    if config.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    if let Some(export) = &config.export_csv {
+        let rows = db::export_csv(&export.db_path, &export.output)?;
+        println!("Exported {} events to {}", rows, export.output.display());
+        return Ok(());
+    }
 
-    let camera_frame_width = if args.len() > 2 {
-        args[2].parse().unwrap_or(default_camera_frame_width)
+    // Initialize MQTT client
+    let mqttoptions = mqtt::build_options(&config.broker).map_err(AppError::Mqtt)?;
+    if !config.cameras.is_empty() {
+        // Discovery and commands describe a single camera, not offered here
+        let mqtt = mqtt::connect(mqttoptions, mqtt::Availability::new(&config.broker), None, None);
+        return multi_camera::run(config, mqtt).await;
+    }
+    let topics = mqtt::Topics::new(&config.broker);
+    let discovery = discovery::Discovery::new(&config, &topics);
+    let (command_topic, mut commands) = if config.commands.enabled {
+        let (topic, commands) = CommandTopic::new(&topics);
+        (Some(topic), Some(commands))
     } else {
-        default_camera_frame_width
+        (None, None)
     };
+    let availability = mqtt::Availability::new(&config.broker);
+    let mqtt = mqtt::connect(mqttoptions, availability.clone(), discovery, command_topic);
+    let mut mqtt_rejected = mqtt.rejected();
+
+    // Initialize the people detector selected in the config
+    let mut detection_worker = DetectionWorker::spawn(&config).await.map_err(AppError::Detection)?;
+    let mut tracker = CentroidTracker::new(&config.tracker);
+    let mut motion = config.motion.enabled.then(|| MotionFilter::new(&config.motion));
+    let mut transitions = config
+        .transitions
+        .enabled
+        .then(|| TransitionDetector::new(config.transitions.debounce_passes));
+    let mut line_counter = config
+        .line_crossing
+        .enabled
+        .then(|| LineCounter::new(&config.line_crossing, config.tracker.max_missed_frames));
+
+    // Open the camera device or stream at the configured resolution
+    let cam = Camera::open_or_discover(&config.camera).await.map_err(AppError::Camera)?;
+    let source = cam.source().label();
+
+    if config.metrics.enabled {
+        metrics::spawn(SocketAddr::from(([0, 0, 0, 0], config.metrics.port)));
+    }
 
-    let camera_frame_height = if args.len() > 3 {
-        args[3].parse().unwrap_or(default_camera_frame_height)
+    // Ctrl-C / SIGTERM stop the loop, in headless mode this is the only way out
+    let mut shutdown = shutdown::listen();
+
+    // Capture runs on its own thread so a slow detection pass drops frames
+    // instead of letting the camera buffer fill up with stale ones
+    let (frame_sender, mut frames) = mpsc::channel(config.camera.frame_queue_capacity);
+    // Shown frames go back to capture to be read into again
+    let (recycle_frames, recycled_frames) = mpsc::channel(config.camera.frame_queue_capacity + 1);
+    let capture_stats = Arc::new(CaptureStats::default());
+    let (camera_sender, mut camera_healthy) = watch::channel(true);
+    let (camera_controls, controls_receiver) = watch::channel(config.camera.controls());
+    let capture = camera::spawn_capture(
+        cam,
+        frame_sender,
+        recycled_frames,
+        Arc::clone(&capture_stats),
+        camera_sender,
+        controls_receiver,
+        shutdown.clone(),
+    );
+    let mut stats_interval = time::interval_at(Instant::now() + STATS_INTERVAL, STATS_INTERVAL);
+    let mut fps_log_interval = time::interval_at(Instant::now() + FPS_LOG_INTERVAL, FPS_LOG_INTERVAL);
+    let display = Display::open(&config.display).map_err(AppError::Display)?;
+    let mut log_file = if config.log_file.enabled {
+        Some(FileLogger::open(&config.log_file).map_err(|source| AppError::Io {
+            path: config.log_file.path.clone(),
+            source,
+        })?)
     } else {
-        default_camera_frame_height
+        None
     };
-
-    let broker_ip = if args.len() > 4 {
-        args[4].clone()
+    let events_db = if config.database.enabled {
+        let store = db::EventStore::open(&config.database.path).map_err(AppError::Database)?;
+        log::info!("Logging detection events to {}", config.database.path.display());
+        Some(store)
     } else {
-        default_broker_ip.clone()
+        None
     };
-
-    let broker_ip_port = if args.len() > 5 {
-        args[5].parse().unwrap_or(default_broker_ip_port)
+    let influx = match &config.influxdb.url {
+        Some(url) => {
+            let camera = config.broker.device_id.as_deref().unwrap_or(&config.broker.client_id);
+            let writer = InfluxWriter::spawn(&config.influxdb, url, camera)
+                .map_err(|e| AppError::Config(ConfigError::Invalid(format!("invalid influxdb.url: {}", e))))?;
+            log::info!("Writing counts to InfluxDB at {}", url);
+            Some(writer)
+        }
+        None => None,
+    };
+    let mut recorder = if config.recording.enabled {
+        let recorder = FrameRecorder::new(&config.recording).map_err(|source| AppError::Io {
+            path: config.recording.dir().to_path_buf(),
+            source,
+        })?;
+        Some(recorder)
     } else {
-        default_broker_ip_port
+        None
     };
 
-    // Initialize MQTT client
-    let mut mqttoptions = MqttOptions::new("person_detector", broker_ip, broker_ip_port);
-    mqttoptions.set_keep_alive(Duration::from_secs(60));
-    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
-    let client = Arc::new(client);
-
-    // Initialize the HOG descriptor
-    let mut hog = HOGDescriptor::default()?;
-    hog.set_svm_detector(&HOGDescriptor::get_default_people_detector()?)?;
+    // One task publishes everything, the loop below never waits on the broker
+    let qos = mqtt::qos(config.broker.qos);
+    let publisher = Publisher::spawn(
+        mqtt.client.clone(),
+        mqtt.state(),
+        qos,
+        config.broker.publish_queue_capacity,
+        config.broker.dead_letter_capacity,
+        config.broker.dead_letter_max_bytes,
+    );
+
+    // Unchanged counts are only re-published as a heartbeat
+    let mut last_published: Option<(usize, Instant)> = None;
+    // Requested resolution until the first frame tells the actual one
+    let mut frame_size = FrameSize {
+        width: config.camera.frame_width as i32,
+        height: config.camera.frame_height as i32,
+    };
 
-    // Open webcam video stream
-    let mut cam = videoio::VideoCapture::new(camera_index, videoio::CAP_ANY)?;
-    if !cam.is_opened()? {
-        panic!("Unable to open default camera!");
+    // Latest detection for clients polling `GET /state`
+    let (detection_state, detection_watch) = watch::channel(DetectionEvent::new(
+        &VectorOfRect::new(),
+        0,
+        frame_size,
+        &[],
+        zone::counts(&config.zones, &VectorOfRect::new()),
+        source.clone(),
+        false,
+    ));
+    if config.http.enabled {
+        http_server::spawn(SocketAddr::from(([0, 0, 0, 0], config.http.port)), detection_watch);
     }
-
-    // Set camera resolution
-    cam.set(videoio::CAP_PROP_FRAME_WIDTH, camera_frame_width)?;
-    cam.set(videoio::CAP_PROP_FRAME_HEIGHT, camera_frame_height)?;
-
-    highgui::named_window("People Detection", highgui::WINDOW_AUTOSIZE)?;
-
-    loop {
-
-        let mut frame = Mat::default();
-        cam.read(&mut frame)?;
-
-        if frame.empty() {
-            time::sleep(Duration::from_millis(1)).await;
-            continue;
+    // Serialized events for WebSocket clients, each client subscribes on connect
+    let ws_events = config.websocket.enabled.then(|| {
+        let (events, _) = broadcast::channel(WS_EVENT_CAPACITY);
+        ws_server::spawn(config.websocket.address, events.clone());
+        events
+    });
+
+    // A video file played as fast as possible waits for the detector instead
+    // of skipping frames, like the capture thread does
+    let wait_for_detector = config.camera.source.is_file() && !config.camera.realtime;
+    // Capture has stopped, the last detection pass is still coming in
+    let mut frames_done = false;
+    let mut boxes = VectorOfRect::new();
+    let mut frames_seen: u64 = 0;
+    let mut fps = FpsCounter::new();
+    let mut detection_fps = FpsCounter::new();
+    let mut stage_timer = StageTimer::new();
+    let mut controls = Controls::default();
+    let mut last_detection: Option<Instant> = None;
+    // When the frame the boxes were found on was captured, the motion filter
+    // carries boxes over to later frames
+    let mut boxes_captured_at = std::time::Instant::now();
+    // Of the frame being handled, set when it arrives from the capture thread
+    let mut frame_captured_at = std::time::Instant::now();
+    // Debounced, what is published and shown in the overlay
+    let mut people_count: usize = 0;
+    // Room for the topic and packet header next to the JPEG
+    let mut snapshots = config.snapshot_publish.enabled.then(|| {
+        SnapshotPublisher::new(
+            &config.snapshot_publish,
+            config.broker.max_packet_size.saturating_sub(topics.snapshot.len() + 16),
+        )
+    });
+    let mut snapshot_due = false;
+    // Printed once a video file source has been played through
+    let mut summary = config.camera.source.is_file().then(Summary::default);
+    let mut snapshot_saver = config.snapshots.enabled.then(|| SnapshotSaver::new(&config.snapshots));
+    #[cfg(feature = "s3-upload")]
+    let s3_uploader = if config.s3_upload.enabled {
+        let uploader = s3::S3Uploader::spawn(&config.s3_upload).map_err(AppError::Config)?;
+        log::info!(
+            "Uploading snapshots to bucket {} at {}",
+            config.s3_upload.bucket,
+            config.s3_upload.endpoint
+        );
+        Some(uploader)
+    } else {
+        None
+    };
+    let mut heatmap = config.heatmap.enabled.then(|| Heatmap::new(&config.heatmap));
+    let mut snapshot_save_due = false;
+    let mut debouncer = Debouncer::new(config.detector.debounce_frames, 0);
+    let mut alert = AlertMonitor::new(&config.alert);
+    let alert_topic = config.alert.topic.clone().unwrap_or_else(|| topics.alert.clone());
+    let mut person_topics = config
+        .broker
+        .publish_individual_detections
+        .then(|| PersonTopics::new(&topics));
+
+    while !*shutdown.borrow() {
+        if frames_done && !detection_worker.is_busy() {
+            break;
+        }
+        let (frame, finished) = tokio::select! {
+            _ = shutdown.changed() => break,
+            Ok(()) = mqtt_rejected.changed() => {
+                if let Some(reason) = mqtt_rejected.borrow_and_update().clone() {
+                    return Err(AppError::MqttAuth(reason));
+                }
+                continue;
+            }
+            _ = stats_interval.tick() => {
+                publish_stats(&publisher, &topics.stats, &capture_stats, detection_fps.fps(), &mut stage_timer);
+                continue;
+            }
+            _ = fps_log_interval.tick() => {
+                log::info!(
+                    "Capture {:.1} FPS, detection {:.1} FPS",
+                    capture_stats.fps(),
+                    detection_fps.fps()
+                );
+                continue;
+            }
+            // Tell subscribers the count is stale while the camera is being reopened
+            Ok(()) = camera_healthy.changed() => {
+                let status = if *camera_healthy.borrow_and_update() {
+                    availability.online()
+                } else {
+                    availability.degraded()
+                };
+                publisher.send_with_qos(&availability.topic, status, true, availability.qos);
+                continue;
+            }
+            Some(command) = next_command(&mut commands) => {
+                if let Command::SetCamera(update) = &command {
+                    camera_controls.send_replace(*update);
+                }
+                if command == Command::ResetCrossings {
+                    match &mut line_counter {
+                        Some(line_counter) => {
+                            line_counter.reset();
+                            log::info!("Line crossing totals reset");
+                            publish_crossings(&publisher, &topics.crossings, line_counter, config.broker.retain);
+                        }
+                        None => log::warn!("Ignoring reset_crossings, line crossing is disabled"),
+                    }
+                }
+                if command == Command::Pause {
+                    // Nothing is detected while paused, don't keep drawing stale boxes
+                    boxes = VectorOfRect::new();
+                }
+                controls.apply(command);
+                continue;
+            }
+            finished = detection_worker.finished(), if detection_worker.is_busy() => match finished {
+                Some(pass) => (None, Some(pass.map_err(AppError::Detection)?)),
+                None => {
+                    return Err(AppError::Detection(opencv::Error::new(
+                        opencv::core::StsError,
+                        "the detection thread stopped".to_string(),
+                    )))
+                }
+            },
+            // None once the capture thread has stopped, its error is reported below
+            frame = frames.recv(), if !frames_done && (!wait_for_detector || !detection_worker.is_busy()) => match frame {
+                Some(CapturedFrame { image, captured_at }) => {
+                    frame_captured_at = captured_at;
+                    (Some(image), None)
+                }
+                None if detection_worker.is_busy() => {
+                    frames_done = true;
+                    continue;
+                }
+                None => break,
+            },
+        };
+
+        // Whether the motion filter let the detector run, the boxes and the
+        // size of the frame they were found on
+        let mut pass: Option<(bool, VectorOfRect, FrameSize)> = None;
+        // Dropped when paused meanwhile, nothing is shown while paused
+        if let Some(finished) = finished.filter(|_| !controls.paused) {
+            log::debug!("Detection took {:.1} ms", finished.latency.as_secs_f64() * 1000.0);
+            metrics::observe_detection(finished.latency);
+            stage_timer.detection(finished.latency);
+            let found = zone::filter(&config.zones, &finished.boxes);
+            if let Some(summary) = &mut summary {
+                summary.add(found.len(), finished.latency);
+            }
+            boxes_captured_at = finished.captured_at;
+            pass = Some((true, found, finished.frame));
         }
 
-        let mut processed_frame = Mat::default();
-        imgproc::cvt_color(&frame, &mut processed_frame, imgproc::COLOR_BGR2GRAY, 0)?;
-
-        let mut boxes = VectorOfRect::new();
-        hog.detect_multi_scale(
-            &processed_frame,
-            &mut boxes,
-            0.88,
-            Size::new(8, 8),
-            Size::new(26, 26),
-            1.03,
-            2.0,
-            false,
-        )?;
-
-        let people_count = boxes.len();
-        let timestamp = get_timestamp();
-        let message = format!(
-            "{} - {}People Detected: {}",
-            colored_log(&timestamp, "\x1b[33m"),
-            colored_log("People Detected: ", "\x1b[37m"),
-            colored_log(&people_count.to_string(), "\x1b[32m")
-        );
+        let mut current_fps = fps.fps();
+        if let Some(frame) = &frame {
+            current_fps = fps.tick();
+
+            // Frames in between reuse the previous boxes and publish nothing,
+            // so do frames arriving while the detector is still busy
+            let detect = frames_seen.is_multiple_of(config.detector.process_every_n)
+                && !controls.paused
+                && !detection_worker.is_busy()
+                && last_detection.is_none_or(|at| at.elapsed() >= controls.detection_interval);
+            frames_seen += 1;
+            if detect {
+                last_detection = Some(Instant::now());
+                // In a static scene the previous boxes are still valid
+                let motion_detected = match &mut motion {
+                    Some(motion) => motion.detect(frame).map_err(AppError::Detection)?,
+                    None => true,
+                };
+                if motion_detected {
+                    // The frame itself still gets drawn on and shown below
+                    detection_worker
+                        .submit(frame, frame_captured_at)
+                        .map_err(AppError::Detection)?;
+                } else {
+                    metrics::MOTION_SKIPPED_FRAMES.inc();
+                    let size = FrameSize {
+                        width: frame.cols(),
+                        height: frame.rows(),
+                    };
+                    pass = Some((false, boxes.clone(), size));
+                }
+            }
+        }
 
-        let send_message = format!("{}", &people_count.to_string());
+        if let Some((motion_detected, found, size)) = pass {
+            let publish_started = Instant::now();
+            let detection_rate = detection_fps.tick();
+            metrics::DETECTION_FPS.set(f64::from(detection_rate));
+            let previous_count = boxes.len();
+            boxes = found;
+            frame_size = size;
+            if let Some(heatmap) = &mut heatmap {
+                if let Err(e) = heatmap.add(opencv::core::Size::new(size.width, size.height), &boxes) {
+                    log::warn!("Failed to update the heatmap: {}", e);
+                }
+                if heatmap.save_due() {
+                    save_heatmap(heatmap);
+                }
+            }
 
+            let tracks = tracker.track(&boxes);
+            log::debug!(
+                "Boxes: {:?}",
+                boxes.iter().map(BoundingBox::from).collect::<Vec<_>>()
+            );
+            if let Some(person_topics) = &mut person_topics {
+                person_topics.publish(&publisher, &tracks);
+            }
+            if let Some(line_counter) = &mut line_counter {
+                let crossings = line_counter.update(&tracks);
+                for crossing in &crossings {
+                    log::info!("Person {} crossed the line: {:?}", crossing.track_id, crossing.direction);
+                    match crossing.to_json() {
+                        Ok(json) => publisher.send(&topics.line, json, false),
+                        Err(e) => log::warn!("Failed to serialize line crossing: {}", e),
+                    }
+                }
+                if !crossings.is_empty() {
+                    publish_crossings(&publisher, &topics.crossings, line_counter, config.broker.retain);
+                }
+            }
 
-        println!("{}", message);
+            // Snapshots want the very frame someone shows up in, everything
+            // published goes by the debounced count
+            let raw_count = boxes.len();
+            people_count = debouncer.update(raw_count);
+            metrics::PEOPLE_COUNT.set(people_count as i64);
+            if let Some(snapshots) = &mut snapshots {
+                snapshot_due |= snapshots.triggered(previous_count, raw_count);
+            }
+            if let Some(snapshot_saver) = &mut snapshot_saver {
+                snapshot_save_due |= snapshot_saver.triggered(previous_count, raw_count);
+            }
+            if let Some(alert) = &mut alert {
+                if alert.update(people_count) {
+                    log::warn!("ALERT: {} people present (threshold {})", people_count, alert.threshold());
+                    let payload = match config.alert.payload {
+                        AlertPayload::Alert => "ALERT".to_string(),
+                        AlertPayload::Count => people_count.to_string(),
+                    };
+                    publisher.send(&alert_topic, payload, false);
+                }
+            }
+            let mut event = DetectionEvent::new(
+                &boxes,
+                people_count,
+                frame_size,
+                &tracks,
+                zone::counts(&config.zones, &boxes),
+                source.clone(),
+                motion_detected,
+            );
+            event.unique_count = Some(tracker.unique_count());
+            event.detection_age_ms = Some(boxes_captured_at.elapsed().as_millis() as u64);
+            // A payload that can't be built only skips the publish, the frame
+            // is still shown, recorded and added to the heatmap
+            let send_message = match build_payload(&event, config.broker.payload_format) {
+                Ok(json) => Some(json),
+                Err(e) => {
+                    log::warn!("Failed to serialize detection event: {}", e);
+                    None
+                }
+            };
+            // Only serialized while someone is connected
+            if let Some(ws_events) = ws_events.as_ref().filter(|events| events.receiver_count() > 0) {
+                match event.to_json() {
+                    Ok(json) => {
+                        let _ = ws_events.send(json);
+                    }
+                    Err(e) => log::warn!("Failed to serialize detection event: {}", e),
+                }
+            }
+            if let Some(events_db) = &events_db {
+                events_db.insert(&event);
+            }
+            if let Some(log_file) = &mut log_file {
+                if let Err(e) = log_file.write(&event) {
+                    log::warn!("Failed to write {}: {}", config.log_file.path.display(), e);
+                }
+            }
+            detection_state.send_replace(event);
+            if let Some(transition) = transitions.as_mut().and_then(|transitions| transitions.update(raw_count)) {
+                match transition.to_json() {
+                    Ok(json) => publisher.send(&topics.event, json, false),
+                    Err(e) => log::warn!("Failed to serialize transition event: {}", e),
+                }
+            }
 
-        let client = Arc::clone(&client);
-        task::spawn(async move {
-            if let Err(e) = client.publish("person_detector", QoS::AtLeastOnce, false, send_message).await {
-                eprintln!("Failed to publish message: {}", e);
-            } else {
-                println!("{}", message);
+            log::info!("People detected: {} ({:.1} FPS)", people_count, detection_rate);
+            if let Some(influx) = &influx {
+                influx.write(people_count, detection_rate);
             }
-        });
 
-        // Draw detected people
-        for rect in boxes.iter() {
-            imgproc::rectangle(
-                &mut frame,
-                rect,
-                core::Scalar::new(0.0, 255.0, 0.0, 0.0),
-                2,
-                imgproc::LINE_AA,
-                0,
-            )?;
+            // Nothing to send counts as not published, the next frame tries again
+            let publish = send_message.and_then(|message| {
+                multi_camera::publish_due(&config.broker, &mut last_published, people_count)
+                    .map(|retain| (message, retain))
+            });
+            if let Some((send_message, retain)) = publish {
+                publisher.send(&topics.count, send_message, retain);
+            }
+            stage_timer.publish(publish_started.elapsed());
         }
 
-        highgui::imshow("People Detection", &frame)?;
-
-        if highgui::wait_key(1)? == 'q' as i32 {
+        let Some(mut frame) = frame else {
+            continue;
+        };
+        // Copied before anything is drawn on the frame
+        let raw_snapshot = match &snapshot_saver {
+            Some(snapshot_saver) if snapshot_save_due && !snapshot_saver.annotated() => {
+                Some(frame.try_clone().map_err(AppError::Display)?)
+            }
+            _ => None,
+        };
+        // Draw detected people, skipped in headless mode unless recording
+        if !display.is_headless()
+            || recorder.is_some()
+            || controls.snapshot_requested
+            || snapshot_due
+            || (snapshot_save_due && raw_snapshot.is_none())
+        {
+            let draw_started = Instant::now();
+            if let Some(heatmap) = &heatmap {
+                heatmap.blend(&mut frame).map_err(AppError::Display)?;
+            }
+            display
+                .annotate(&mut frame, &config.zones, &boxes)
+                .map_err(AppError::Display)?;
+            if line_counter.is_some() {
+                display
+                    .draw_counting_line(&mut frame, config.line_crossing.line())
+                    .map_err(AppError::Display)?;
+            }
+            display.draw_fps(&mut frame, current_fps).map_err(AppError::Display)?;
+            display
+                .draw_overlay(&mut frame, people_count)
+                .map_err(AppError::Display)?;
+            stage_timer.draw(draw_started.elapsed());
+        }
+        if let Some(recorder) = &mut recorder {
+            recorder.update(&frame, boxes.len()).map_err(AppError::Recording)?;
+        }
+        if controls.snapshot_requested {
+            controls.snapshot_requested = false;
+            match snapshot::save(&config.commands.snapshot_dir, &frame) {
+                Ok(path) => log::info!("Saved snapshot {}", path.display()),
+                Err(e) => log::warn!("Failed to save snapshot: {}", e),
+            }
+        }
+        if snapshot_save_due {
+            snapshot_save_due = false;
+            if let Some(snapshot_saver) = &snapshot_saver {
+                match snapshot_saver.save(raw_snapshot.as_ref().unwrap_or(&frame), &boxes, &source) {
+                    Ok(path) => {
+                        log::info!("Saved snapshot {}", path.display());
+                        #[cfg(feature = "s3-upload")]
+                        if let Some(s3_uploader) = &s3_uploader {
+                            s3_uploader.upload(&path);
+                        }
+                    }
+                    Err(e) => log::warn!("Failed to save snapshot: {}", e),
+                }
+            }
+        }
+        if snapshot_due {
+            snapshot_due = false;
+            if let Some(snapshots) = &snapshots {
+                match snapshots.encode(&frame) {
+                    Ok(jpeg) => publisher.send(&topics.snapshot, jpeg, false),
+                    Err(e) => log::warn!("Failed to encode snapshot: {}", e),
+                }
+            }
+        }
+        let display_started = Instant::now();
+        let open = display.show(&frame).map_err(AppError::Display)?;
+        stage_timer.display(display_started.elapsed());
+        if !open {
             break;
         }
+        // Capture allocates a new one when the queue is full
+        let _ = recycle_frames.try_send(frame);
+    }
+    if let Some(summary) = &summary {
+        summary.print();
+    }
+    // Keeps what was collected since the last periodic save
+    if let Some(heatmap) = &mut heatmap {
+        save_heatmap(heatmap);
+    }
 
-        // Poll MQTT event loop
-        eventloop.poll().await.expect("Failed to publish message to broker !");
+    // Report an empty scene so subscribers don't keep a stale count
+    publisher.stop(SHUTDOWN_TIMEOUT).await;
+    let nobody = VectorOfRect::new();
+    let last_event = DetectionEvent::new(
+        &nobody,
+        0,
+        frame_size,
+        &[],
+        zone::counts(&config.zones, &nobody),
+        source,
+        false,
+    );
+    match build_payload(&last_event, config.broker.payload_format) {
+        Ok(last_message) => {
+            // Replaces a retained count, subscribers shouldn't see people after shutdown
+            let retain = config.broker.retain || config.broker.heartbeat_retain;
+            let last_message = publisher::Message {
+                topic: topics.count.clone(),
+                payload: last_message.into_bytes(),
+                retain,
+                qos: None,
+            };
+            mqtt.close(&[last_message], qos, SHUTDOWN_TIMEOUT).await
+        }
+        Err(e) => log::warn!("Failed to serialize detection event: {}", e),
     }
 
-    cam.release()?;
-    highgui::destroy_all_windows()?;
+    drop(frames);
+    // A camera that hangs in open or read mustn't keep the rest from shutting down
+    match time::timeout(SHUTDOWN_TIMEOUT, capture).await {
+        Ok(Ok(result)) => result.map_err(AppError::Camera)?,
+        Ok(Err(e)) => log::error!("Capture task failed: {}", e),
+        Err(_) => log::warn!("Camera didn't stop within {:?}, not waiting for it", SHUTDOWN_TIMEOUT),
+    }
+    if let Some(recorder) = &mut recorder {
+        recorder.stop().map_err(AppError::Recording)?;
+    }
+    if let Some(events_db) = events_db {
+        events_db.close().await;
+    }
+    if let Some(influx) = influx {
+        if time::timeout(SHUTDOWN_TIMEOUT, influx.close()).await.is_err() {
+            log::warn!("InfluxDB didn't take the last points in time");
+        }
+    }
+    #[cfg(feature = "s3-upload")]
+    if let Some(s3_uploader) = s3_uploader {
+        if time::timeout(SHUTDOWN_TIMEOUT, s3_uploader.close()).await.is_err() {
+            log::warn!("S3 uploads didn't finish in time, the snapshots are still on disk");
+        }
+    }
+    display.close().map_err(AppError::Display)?;
 
     Ok(())
 }
@@ -0,0 +1,192 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use prometheus::{
+    register_gauge, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Encoder, Gauge,
+    Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+// All metrics live in the default registry, which is what `/metrics` serves
+
+const DETECTION_BUCKETS_SECONDS: [f64; 7] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+pub static PEOPLE_COUNT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("people_detector_people_count", "People detected in the latest frame")
+        .expect("metric can be registered")
+});
+
+pub static FRAMES_PROCESSED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("people_detector_frames_processed_total", "Frames run through the detector")
+        .expect("metric can be registered")
+});
+
+pub static FRAMES_DROPPED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "people_detector_frames_dropped_total",
+        "Captured frames dropped because detection was still busy"
+    )
+    .expect("metric can be registered")
+});
+
+pub static MOTION_SKIPPED_FRAMES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "people_detector_motion_skipped_frames_total",
+        "Frames not run through the detector because nothing moved"
+    )
+    .expect("metric can be registered")
+});
+
+pub static DETECTION_LATENCY_MS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "people_detector_detection_latency_ms",
+        "Time spent detecting people in one frame, in milliseconds",
+        vec![10.0, 25.0, 50.0, 100.0, 250.0, 500.0]
+    )
+    .expect("metric can be registered")
+});
+
+// Same in seconds as Prometheus prefers, the milliseconds stay for existing dashboards
+pub static DETECTION_DURATION_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "people_detector_detection_duration_seconds",
+        "Time spent detecting people in one frame",
+        DETECTION_BUCKETS_SECONDS.to_vec()
+    )
+    .expect("metric can be registered")
+});
+
+pub static DETECTION_FPS: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "people_detector_detection_fps",
+        "Frames per second through the detection loop, over the last 30 frames"
+    )
+    .expect("metric can be registered")
+});
+
+pub static CAMERA_FPS: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!("people_detector_camera_fps", "Frame rate the camera reported when it was opened")
+        .expect("metric can be registered")
+});
+
+pub static MQTT_PUBLISH_ERRORS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("people_detector_mqtt_publish_errors_total", "MQTT publishes that failed")
+        .expect("metric can be registered")
+});
+
+pub static MQTT_RECONNECTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("people_detector_mqtt_reconnects_total", "Attempts to reconnect to the MQTT broker")
+        .expect("metric can be registered")
+});
+
+// With several cameras, labelled with the camera's device_id. The metrics
+// above then hold the total count and all cameras' frames.
+
+pub static CAMERA_PEOPLE_COUNT: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "people_detector_camera_people_count",
+        "People detected in the latest frame of a camera",
+        &["camera"]
+    )
+    .expect("metric can be registered")
+});
+
+pub static CAMERA_FRAMES_PROCESSED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "people_detector_camera_frames_processed_total",
+        "Frames of a camera run through the detector",
+        &["camera"]
+    )
+    .expect("metric can be registered")
+});
+
+pub static CAMERA_DETECTION_DURATION_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "people_detector_camera_detection_duration_seconds",
+        "Time spent detecting people in one frame of a camera",
+        &["camera"],
+        DETECTION_BUCKETS_SECONDS.to_vec()
+    )
+    .expect("metric can be registered")
+});
+
+// Updated with the stats topic, a slow leak shows up over days
+pub static RESIDENT_MEMORY_BYTES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "people_detector_resident_memory_bytes",
+        "Physical memory used by the process"
+    )
+    .expect("metric can be registered")
+});
+
+// From /proc, None on systems without it
+pub fn resident_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+// Records one detection pass in both detection time histograms
+pub fn observe_detection(duration: Duration) {
+    DETECTION_LATENCY_MS.observe(duration.as_secs_f64() * 1000.0);
+    DETECTION_DURATION_SECONDS.observe(duration.as_secs_f64());
+    FRAMES_PROCESSED.inc();
+}
+
+// Serves `GET /metrics` in the background, a failing server is logged but
+// never stops detection
+pub fn spawn(addr: SocketAddr) {
+    // Register everything up front so the first scrape lists all metrics
+    LazyLock::force(&PEOPLE_COUNT);
+    LazyLock::force(&FRAMES_PROCESSED);
+    LazyLock::force(&FRAMES_DROPPED);
+    LazyLock::force(&MOTION_SKIPPED_FRAMES);
+    LazyLock::force(&DETECTION_LATENCY_MS);
+    LazyLock::force(&DETECTION_DURATION_SECONDS);
+    LazyLock::force(&DETECTION_FPS);
+    LazyLock::force(&CAMERA_FPS);
+    LazyLock::force(&MQTT_PUBLISH_ERRORS);
+    LazyLock::force(&MQTT_RECONNECTS);
+
+    tokio::spawn(async move {
+        let server = match Server::try_bind(&addr) {
+            Ok(server) => server,
+            Err(e) => {
+                log::error!("Unable to start metrics server on {}: {}", addr, e);
+                return;
+            }
+        };
+        log::info!("Serving Prometheus metrics on http://{}/metrics", addr);
+        let service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+        if let Err(e) = server.serve(service).await {
+            log::error!("Metrics server failed: {}", e);
+        }
+    });
+}
+
+async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::from("not found\n"));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::warn!("Failed to encode metrics: {}", e);
+        let mut response = Response::new(Body::from("failed to encode metrics\n"));
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return Ok(response);
+    }
+
+    let mut response = Response::new(Body::from(buffer));
+    if let Ok(content_type) = encoder.format_type().parse() {
+        response.headers_mut().insert(header::CONTENT_TYPE, content_type);
+    }
+    Ok(response)
+}
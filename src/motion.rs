@@ -0,0 +1,94 @@
+use opencv::{
+    core::{self, Mat, Size},
+    imgproc,
+    prelude::*,
+};
+
+use crate::config::MotionConfig;
+
+// Motion is checked on a small copy of the frame, plenty to notice a person
+const WORK_WIDTH: i32 = 320;
+// Gray level difference from the background that counts as a changed pixel
+const PIXEL_THRESHOLD: f64 = 25.0;
+// How quickly the background takes on the latest frame, a person standing
+// still fades into it after a few seconds
+const LEARNING_RATE: f64 = 0.05;
+
+// Cheap frame differencing in front of the detector: while the scene doesn't
+// change, the previous detection result is still valid and HOG can be skipped
+pub struct MotionFilter {
+    threshold_pct: f64,
+    // Running average of the blurred gray frames, CV_32F
+    background: Option<Mat>,
+    // Intermediate images, reused so a pass at the same size doesn't allocate
+    small: Mat,
+    gray: Mat,
+    blurred: Mat,
+    reference: Mat,
+    difference: Mat,
+    changed: Mat,
+}
+
+impl MotionFilter {
+    pub fn new(config: &MotionConfig) -> Self {
+        MotionFilter {
+            threshold_pct: config.threshold_pct,
+            background: None,
+            small: Mat::default(),
+            gray: Mat::default(),
+            blurred: Mat::default(),
+            reference: Mat::default(),
+            difference: Mat::default(),
+            changed: Mat::default(),
+        }
+    }
+
+    // Whether at least `threshold_pct` percent of the frame changed compared
+    // to the background, always true for the first frame
+    pub fn detect(&mut self, frame: &Mat) -> opencv::Result<bool> {
+        self.prepare(frame)?;
+        let Some(background) = &mut self.background else {
+            let mut background = Mat::default();
+            self.blurred.convert_to(&mut background, core::CV_32F, 1.0, 0.0)?;
+            self.background = Some(background);
+            return Ok(true);
+        };
+
+        background.convert_to(&mut self.reference, core::CV_8U, 1.0, 0.0)?;
+        core::absdiff(&self.blurred, &self.reference, &mut self.difference)?;
+        imgproc::threshold(
+            &self.difference,
+            &mut self.changed,
+            PIXEL_THRESHOLD,
+            255.0,
+            imgproc::THRESH_BINARY,
+        )?;
+        let changed_pct = core::count_non_zero(&self.changed)? as f64 * 100.0 / self.changed.total() as f64;
+
+        imgproc::accumulate_weighted(&self.blurred, background, LEARNING_RATE, &core::no_array())?;
+        Ok(changed_pct >= self.threshold_pct)
+    }
+
+    // Small, gray and blurred so sensor noise doesn't look like motion,
+    // leaves the result in `blurred`
+    fn prepare(&mut self, frame: &Mat) -> opencv::Result<()> {
+        let height = (frame.rows() as f64 * WORK_WIDTH as f64 / frame.cols() as f64).round() as i32;
+        imgproc::resize(
+            frame,
+            &mut self.small,
+            Size::new(WORK_WIDTH, height.max(1)),
+            0.0,
+            0.0,
+            imgproc::INTER_AREA,
+        )?;
+        imgproc::cvt_color(&self.small, &mut self.gray, imgproc::COLOR_BGR2GRAY, 0)?;
+        imgproc::gaussian_blur(
+            &self.gray,
+            &mut self.blurred,
+            Size::new(5, 5),
+            0.0,
+            0.0,
+            core::BORDER_DEFAULT,
+        )
+    }
+}
@@ -0,0 +1,609 @@
+use rumqttc::{
+    AsyncClient, ConnectReturnCode, ConnectionError, Event, EventLoop, LastWill, MqttOptions,
+    Outgoing, Packet, Publish, QoS, TlsConfiguration, Transport,
+};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::backoff::Backoff;
+use crate::commands::CommandTopic;
+use crate::config::{BrokerConfig, ConfigError, PayloadFormat};
+use crate::discovery::Discovery;
+use crate::metrics;
+use crate::publisher::Message;
+use crate::tls;
+
+// Capacity of the request channel between the client and the event loop
+const REQUEST_CHANNEL_CAPACITY: usize = 10;
+// Upper bound for the delay between two connection attempts
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+// Status values on the availability topic, see `Availability`
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+// Connected, but the camera stopped delivering frames so the count is stale
+pub const DEGRADED: &str = "degraded";
+
+// Retained payloads on the availability topic. Plain is the bare status,
+// JSON `{"status":"online"}` with a `reason` on a graceful shutdown.
+#[derive(Debug, Clone)]
+pub struct Availability {
+    pub topic: String,
+    pub qos: QoS,
+    pub format: PayloadFormat,
+    // Published by the broker when we vanish without a disconnect
+    pub last_will: String,
+}
+
+impl Availability {
+    pub fn new(broker: &BrokerConfig) -> Self {
+        let format = broker.availability_format;
+        Availability {
+            topic: Topics::new(broker).availability,
+            qos: qos(broker.lwt_qos),
+            format,
+            last_will: broker
+                .lwt_payload
+                .clone()
+                .unwrap_or_else(|| status_payload(format, OFFLINE, None)),
+        }
+    }
+
+    pub fn online(&self) -> String {
+        status_payload(self.format, ONLINE, None)
+    }
+
+    pub fn degraded(&self) -> String {
+        status_payload(self.format, DEGRADED, None)
+    }
+
+    pub fn shutdown(&self) -> String {
+        status_payload(self.format, OFFLINE, Some("graceful_shutdown"))
+    }
+}
+
+fn status_payload(format: PayloadFormat, status: &str, reason: Option<&str>) -> String {
+    match (format, reason) {
+        (PayloadFormat::Plain, _) => status.to_string(),
+        (PayloadFormat::Json, None) => json!({ "status": status }).to_string(),
+        (PayloadFormat::Json, Some(reason)) => json!({ "status": status, "reason": reason }).to_string(),
+    }
+}
+
+// Maps the configured QoS level, anything above 2 is rejected when the config is validated
+pub fn qos(level: u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
+
+// Topics this detector publishes to. With a device id everything lives under
+// <base>/<device-id>/ so several detectors on one broker don't collide,
+// without one counts keep going to <base> itself as they always did.
+#[derive(Debug, Clone)]
+pub struct Topics {
+    // Base topic, with the device id appended when one is set
+    pub prefix: String,
+    pub count: String,
+    // Retained online/offline, see `Availability`
+    pub availability: String,
+    pub stats: String,
+    // Enter/leave events, see `event::TransitionEvent`
+    pub event: String,
+    // In/out line crossings, see `event::CrossingEvent`, and their totals
+    pub line: String,
+    pub crossings: String,
+    // See `alert::AlertMonitor`
+    pub alert: String,
+    // JPEG bytes, see `snapshot::SnapshotPublisher`
+    pub snapshot: String,
+    // Runtime commands, see `commands::Command`, acknowledged on `command_ack`
+    pub command: String,
+    pub command_ack: String,
+}
+
+impl Topics {
+    pub fn new(broker: &BrokerConfig) -> Self {
+        let (prefix, count) = match &broker.device_id {
+            Some(device_id) => {
+                let prefix = format!("{}/{}", broker.topic, device_id);
+                let count = format!("{}/count", prefix);
+                (prefix, count)
+            }
+            None => (broker.topic.clone(), broker.topic.clone()),
+        };
+        Topics {
+            count,
+            availability: broker
+                .lwt_topic
+                .clone()
+                .unwrap_or_else(|| format!("{}/{}", prefix, broker.availability_subtopic)),
+            stats: format!("{}/stats", prefix),
+            event: format!("{}/event", prefix),
+            line: format!("{}/line", prefix),
+            crossings: format!("{}/crossings", prefix),
+            alert: format!("{}/alert", prefix),
+            snapshot: format!("{}/snapshot", prefix),
+            command: format!("{}/cmd", prefix),
+            command_ack: format!("{}/cmd/ack", prefix),
+            prefix,
+        }
+    }
+}
+
+// Two instances connecting with the same id would keep kicking each other off
+pub fn client_id(broker: &BrokerConfig) -> String {
+    match &broker.device_id {
+        Some(device_id) => format!("{}_{}", broker.client_id, device_id),
+        None => broker.client_id.clone(),
+    }
+}
+
+pub fn build_options(broker: &BrokerConfig) -> Result<MqttOptions, ConfigError> {
+    let mut options = MqttOptions::new(client_id(broker), broker.ip.clone(), broker.port);
+    options.set_keep_alive(Duration::from_secs(60));
+    options.set_max_packet_size(broker.max_packet_size, broker.max_packet_size);
+    // The broker publishes this for us when the connection drops without a disconnect
+    let availability = Availability::new(broker);
+    options.set_last_will(LastWill::new(
+        availability.topic,
+        availability.last_will,
+        availability.qos,
+        true,
+    ));
+    if let Some(username) = &broker.username {
+        options.set_credentials(username, broker.password.as_deref().unwrap_or_default());
+    }
+    if broker.tls_enabled() {
+        if broker.client_cert_path.is_some() {
+            log::info!("MQTT over TLS with client certificate authentication");
+        } else {
+            log::info!("MQTT over TLS with server authentication only");
+        }
+        if broker.port == 1883 {
+            log::warn!(
+                "TLS is enabled but broker port is 1883, the plain MQTT port; brokers usually serve TLS on 8883"
+            );
+        }
+        let config = tls::client_config(broker)?;
+        options.set_transport(Transport::tls_with_config(TlsConfiguration::Rustls(Arc::new(config))));
+    }
+    Ok(options)
+}
+
+// A broker connection whose event loop is polled by a background task, so
+// keep-alives and acks are serviced independently of the frame rate
+pub struct Connection {
+    pub client: AsyncClient,
+    connected: watch::Receiver<bool>,
+    event_loop: JoinHandle<()>,
+    availability: Availability,
+    // Retained discovery configs removed again by `close`
+    discovery_topics: Vec<String>,
+    rejected: watch::Receiver<Option<String>>,
+}
+
+pub fn connect(
+    options: MqttOptions,
+    availability: Availability,
+    discovery: Option<Discovery>,
+    commands: Option<CommandTopic>,
+) -> Connection {
+    let (host, port) = options.broker_address();
+    let broker = format!("{}:{}", host, port);
+    let (client, eventloop) = AsyncClient::new(options, REQUEST_CHANNEL_CAPACITY);
+    let (state, connected) = watch::channel(false);
+    let (reject, rejected) = watch::channel(None);
+    let (discovery, discovery_topics) = match discovery {
+        Some(discovery) => {
+            let topics = if discovery.clean {
+                discovery.messages.iter().map(|message| message.topic.clone()).collect()
+            } else {
+                Vec::new()
+            };
+            (discovery.messages, topics)
+        }
+        None => (Vec::new(), Vec::new()),
+    };
+    let session = Session {
+        client: client.clone(),
+        availability: availability.clone(),
+        discovery,
+        commands,
+    };
+    Connection {
+        client,
+        connected,
+        event_loop: tokio::spawn(drive(eventloop, state, reject, broker, session)),
+        availability,
+        discovery_topics,
+        rejected,
+    }
+}
+
+// What the event loop task does on its own: after every (re)connect it sends
+// the discovery configs, so a restarted broker learns about our entities
+// again, then the retained `online` message and the command subscription.
+// Incoming command messages are answered here as well.
+struct Session {
+    client: AsyncClient,
+    availability: Availability,
+    discovery: Vec<Message>,
+    commands: Option<CommandTopic>,
+}
+
+impl Session {
+    fn announce(&self) {
+        // Queued without waiting, the event loop polling us is what drains the queue
+        for message in &self.discovery {
+            if let Err(e) = self.client.try_publish(
+                &message.topic,
+                QoS::AtLeastOnce,
+                message.retain,
+                message.payload.clone(),
+            ) {
+                log::warn!("Failed to publish discovery config to {}: {}", message.topic, e);
+            }
+        }
+        let availability = &self.availability;
+        if let Err(e) = self.client.try_publish(
+            &availability.topic,
+            availability.qos,
+            true,
+            availability.online(),
+        ) {
+            log::warn!("Failed to publish availability to {}: {}", availability.topic, e);
+        }
+        if let Some(commands) = &self.commands {
+            commands.subscribe(&self.client);
+        }
+    }
+
+    fn receive(&self, publish: &Publish) {
+        if let Some(commands) = &self.commands {
+            commands.handle(&self.client, publish);
+        }
+    }
+}
+
+// rumqttc reconnects on the next poll after an error, so this only has to
+// wait between attempts and track whether the broker is reachable
+// Keeps reconnecting until disconnected, except when the broker refuses our
+// credentials: retrying can't fix that, so the reason is sent on `reject`
+async fn drive(
+    mut eventloop: EventLoop,
+    state: watch::Sender<bool>,
+    reject: watch::Sender<Option<String>>,
+    broker: String,
+    session: Session,
+) {
+    let mut backoff = Backoff::new(Duration::from_secs(1), MAX_RECONNECT_DELAY);
+    let mut attempt: u32 = 0;
+    log::info!("Connecting to MQTT broker {}", broker);
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                log::info!("Connected to MQTT broker {}", broker);
+                backoff.reset();
+                attempt = 0;
+                session.announce();
+                state.send_replace(true);
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => session.receive(&publish),
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                log::info!("Disconnecting from MQTT broker {}", broker);
+                state.send_replace(false);
+                return;
+            }
+            Ok(_) => {}
+            Err(e @ ConnectionError::ConnectionRefused(
+                ConnectReturnCode::BadUserNamePassword | ConnectReturnCode::NotAuthorized,
+            )) => {
+                state.send_replace(false);
+                let reason = describe(&e);
+                log::error!("AUTH_FAILED: MQTT broker {} refused the connection: {}", broker, reason);
+                reject.send_replace(Some(reason));
+                return;
+            }
+            Err(e) => {
+                if state.send_replace(false) {
+                    log::warn!("Disconnected from MQTT broker {}: {}", broker, describe(&e));
+                } else {
+                    log::error!("MQTT connection to {} failed: {}", broker, describe(&e));
+                }
+                attempt += 1;
+                metrics::MQTT_RECONNECTS.inc();
+                let delay = backoff.next_delay();
+                log::warn!(
+                    "Reconnecting to MQTT broker {} in {:?} (attempt {})",
+                    broker,
+                    delay,
+                    attempt
+                );
+                time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+// Tells a rejected login apart from a broker that can't be reached at all
+fn describe(error: &ConnectionError) -> String {
+    match error {
+        ConnectionError::ConnectionRefused(ConnectReturnCode::BadUserNamePassword) => {
+            "bad credentials, the broker rejected the username or password".to_string()
+        }
+        ConnectionError::ConnectionRefused(ConnectReturnCode::NotAuthorized) => {
+            "not authorized, the broker requires valid credentials".to_string()
+        }
+        ConnectionError::ConnectionRefused(code) => {
+            format!("connection refused by the broker ({:?})", code)
+        }
+        ConnectionError::Io(e) => format!("broker unreachable: {}", e),
+        e => e.to_string(),
+    }
+}
+
+impl Connection {
+    pub fn is_connected(&self) -> bool {
+        *self.connected.borrow()
+    }
+
+    // Set to the reason once the broker rejected our credentials, the event
+    // loop has given up at that point
+    pub fn rejected(&self) -> watch::Receiver<Option<String>> {
+        self.rejected.clone()
+    }
+
+    // Follows the connection state, `true` while the broker is reachable
+    pub fn state(&self) -> watch::Receiver<bool> {
+        self.connected.clone()
+    }
+
+    // Publishes the last messages and `offline`, then disconnects cleanly, giving up after `timeout`
+    pub async fn close(mut self, last_messages: &[Message], qos: QoS, timeout: Duration) {
+        if !self.is_connected() {
+            self.event_loop.abort();
+            return;
+        }
+
+        let client = &self.client;
+        let event_loop = &mut self.event_loop;
+        let flush = async {
+            for message in last_messages {
+                client
+                    .publish(
+                        &message.topic,
+                        message.qos.unwrap_or(qos),
+                        message.retain,
+                        message.payload.clone(),
+                    )
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            client
+                .publish(
+                    &self.availability.topic,
+                    self.availability.qos,
+                    true,
+                    self.availability.shutdown(),
+                )
+                .await
+                .map_err(|e| e.to_string())?;
+            // An empty retained config removes the entity from Home Assistant
+            for topic in &self.discovery_topics {
+                client
+                    .publish(topic, QoS::AtLeastOnce, true, "")
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            client.disconnect().await.map_err(|e| e.to_string())?;
+            // The requests only reach the broker while the event loop is polled
+            event_loop.await.map_err(|e| e.to_string())
+        };
+
+        match time::timeout(timeout, flush).await {
+            Ok(Ok(())) => log::info!("Disconnected from MQTT broker"),
+            Ok(Err(e)) => log::warn!("Unclean MQTT disconnect: {}", e),
+            Err(_) => log::warn!(
+                "MQTT broker did not respond within {:?}, exiting anyway",
+                timeout
+            ),
+        }
+        self.event_loop.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::publisher::Publisher;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    // Long enough for a reconnect, the first one waits 1 s
+    const TIMEOUT: Duration = Duration::from_secs(10);
+    // Packet types in the high bits of the first header byte
+    const CONNECT: u8 = 1;
+    const PUBLISH: u8 = 3;
+
+    // The broker side of one connection, speaking just enough MQTT 3.1.1
+    // since there is no broker to test against
+    struct FakeBroker {
+        stream: TcpStream,
+    }
+
+    impl FakeBroker {
+        // Takes the next connection and answers its CONNECT with `return_code`
+        async fn accept(listener: &TcpListener, return_code: u8) -> FakeBroker {
+            let (stream, _) = time::timeout(TIMEOUT, listener.accept())
+                .await
+                .expect("client connects in time")
+                .expect("connection is accepted");
+            let mut broker = FakeBroker { stream };
+            let (header, _) = broker.read_packet().await;
+            assert_eq!(header >> 4, CONNECT);
+            broker.write(&[0x20, 0x02, 0x00, return_code]).await;
+            broker
+        }
+
+        // The first header byte and everything after the remaining length
+        async fn read_packet(&mut self) -> (u8, Vec<u8>) {
+            let stream = &mut self.stream;
+            let read = async {
+                let header = stream.read_u8().await.expect("packet header");
+                let mut length = 0;
+                for shift in [0, 7, 14, 21] {
+                    let byte = stream.read_u8().await.expect("remaining length");
+                    length |= usize::from(byte & 0x7f) << shift;
+                    if byte & 0x80 == 0 {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).await.expect("packet body");
+                (header, body)
+            };
+            time::timeout(TIMEOUT, read).await.expect("client sends a packet in time")
+        }
+
+        // Skips everything else, e.g. the retained `online`, until a PUBLISH
+        // to `topic`. Returns its QoS, packet id (0 for QoS 0) and payload.
+        async fn expect_publish(&mut self, topic: &str) -> (u8, u16, Vec<u8>) {
+            loop {
+                let (header, body) = self.read_packet().await;
+                if header >> 4 != PUBLISH {
+                    continue;
+                }
+                let topic_end = 2 + usize::from(u16::from_be_bytes([body[0], body[1]]));
+                if &body[2..topic_end] != topic.as_bytes() {
+                    continue;
+                }
+                let qos = (header >> 1) & 0x03;
+                if qos == 0 {
+                    return (qos, 0, body[topic_end..].to_vec());
+                }
+                let id = u16::from_be_bytes([body[topic_end], body[topic_end + 1]]);
+                return (qos, id, body[topic_end + 2..].to_vec());
+            }
+        }
+
+        async fn write(&mut self, packet: &[u8]) {
+            self.stream.write_all(packet).await.expect("client reads");
+        }
+    }
+
+    async fn start() -> (TcpListener, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("a free port");
+        let port = listener.local_addr().expect("bound address").port();
+        let options = MqttOptions::new("person_detector_test", "127.0.0.1", port);
+        let availability = Availability {
+            topic: "test/status".to_string(),
+            qos: QoS::AtMostOnce,
+            format: PayloadFormat::Plain,
+            last_will: OFFLINE.to_string(),
+        };
+        (listener, connect(options, availability, None, None))
+    }
+
+    fn publisher(connection: &Connection, qos: QoS) -> Publisher {
+        Publisher::spawn(connection.client.clone(), connection.state(), qos, 16, 100, 1 << 20)
+    }
+
+    async fn wait_for_state(state: &mut watch::Receiver<bool>, connected: bool) {
+        time::timeout(TIMEOUT, state.wait_for(|state| *state == connected))
+            .await
+            .expect("connection state changes in time")
+            .expect("event loop is running");
+    }
+
+    #[tokio::test]
+    async fn buffered_counts_are_published_after_a_broker_restart() {
+        let (listener, connection) = start().await;
+        let mut state = connection.state();
+        let publisher = publisher(&connection, QoS::AtMostOnce);
+
+        let mut broker = FakeBroker::accept(&listener, 0).await;
+        wait_for_state(&mut state, true).await;
+        publisher.send("test/count", "1", false);
+        assert_eq!(broker.expect_publish("test/count").await.2, b"1");
+
+        // The broker goes away, detection keeps producing counts meanwhile
+        drop(broker);
+        wait_for_state(&mut state, false).await;
+        publisher.send("test/count", "2", false);
+        publisher.send("test/count", "3", false);
+
+        let mut broker = FakeBroker::accept(&listener, 0).await;
+        wait_for_state(&mut state, true).await;
+        assert_eq!(broker.expect_publish("test/count").await.2, b"2");
+        assert_eq!(broker.expect_publish("test/count").await.2, b"3");
+        publisher.send("test/count", "4", false);
+        assert_eq!(broker.expect_publish("test/count").await.2, b"4");
+    }
+
+    #[tokio::test]
+    async fn qos_2_publish_completes_the_handshake() {
+        let (listener, connection) = start().await;
+        let mut state = connection.state();
+        let publisher = publisher(&connection, QoS::ExactlyOnce);
+
+        let mut broker = FakeBroker::accept(&listener, 0).await;
+        wait_for_state(&mut state, true).await;
+        publisher.send("test/count", "1", false);
+        let (qos, id, payload) = broker.expect_publish("test/count").await;
+        assert_eq!((qos, payload.as_slice()), (2, b"1".as_slice()));
+
+        let [high, low] = id.to_be_bytes();
+        broker.write(&[0x50, 0x02, high, low]).await; // PUBREC
+        // PUBREL has the reserved QoS 1 flags set
+        assert_eq!(broker.read_packet().await, (0x62, vec![high, low]));
+        broker.write(&[0x70, 0x02, high, low]).await; // PUBCOMP
+
+        // rumqttc drops the connection on a PUBCOMP it didn't expect, so the
+        // next publish going out on the same connection means it was accepted
+        publisher.send("test/count", "2", false);
+        let (qos, _, payload) = broker.expect_publish("test/count").await;
+        assert_eq!((qos, payload.as_slice()), (2, b"2".as_slice()));
+        assert!(connection.is_connected());
+    }
+
+    // Connects once, returns the reason the connection was refused for
+    async fn refused_with(return_code: u8) -> String {
+        let (listener, connection) = start().await;
+        let mut rejected = connection.rejected();
+        let _broker = FakeBroker::accept(&listener, return_code).await;
+        let reason = time::timeout(TIMEOUT, rejected.wait_for(Option::is_some))
+            .await
+            .expect("refusal is reported in time")
+            .expect("reason is kept")
+            .clone()
+            .unwrap_or_default();
+
+        time::timeout(TIMEOUT, async {
+            while !connection.event_loop.is_finished() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("event loop stops");
+        assert!(!connection.is_connected());
+        // A retry would be back after 1 s
+        assert!(time::timeout(Duration::from_secs(2), listener.accept()).await.is_err());
+        reason
+    }
+
+    #[tokio::test]
+    async fn bad_credentials_stop_reconnecting() {
+        assert!(refused_with(4).await.starts_with("bad credentials"));
+    }
+
+    #[tokio::test]
+    async fn not_authorized_stops_reconnecting() {
+        assert!(refused_with(5).await.starts_with("not authorized"));
+    }
+}
@@ -0,0 +1,401 @@
+use opencv::{core::Mat, prelude::*, types::VectorOfRect};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
+use tokio::task::{self, JoinSet};
+use tokio::time::{self, Duration, Instant};
+
+use crate::camera::{self, Camera, CapturedFrame};
+use crate::config::{BrokerConfig, CameraConfig, Config};
+use crate::debounce::Debouncer;
+use crate::detector::create_detector;
+use crate::error::AppError;
+use crate::event::{build_payload, DetectionEvent, FrameSize, TotalEvent};
+use crate::mqtt::{self, Connection, Topics};
+use crate::publisher::{Message, Publisher};
+use crate::tracker::CentroidTracker;
+use crate::zone;
+use crate::{metrics, shutdown, SHUTDOWN_TIMEOUT};
+
+// Detection passes queued for publishing, a camera waits once it is this far ahead
+const REPORT_QUEUE_CAPACITY: usize = 16;
+// Wait before a failed camera is started again
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+// Index into `config.cameras` and how the camera task ended
+type CameraTasks = JoinSet<(usize, Result<(), AppError>)>;
+
+struct Report {
+    // Index into `config.cameras`
+    camera: usize,
+    event: DetectionEvent,
+}
+
+struct CameraState {
+    device_id: String,
+    count_topic: String,
+    source: String,
+    frame: FrameSize,
+    count: usize,
+    last_published: Option<(usize, Instant)>,
+}
+
+// Runs every camera in `config.cameras` on its own capture thread and
+// detection thread, all sharing the MQTT connection. Each camera publishes
+// to <topic>/<device_id>/count and the sum goes to <topic>/total. A camera
+// that fails to open or gives up reconnecting is reported as empty and
+// started again after `RESTART_DELAY`, the others keep running.
+pub async fn run(config: Config, mqtt: Connection) -> Result<(), AppError> {
+    let config = Arc::new(config);
+    if !config.display.headless {
+        log::info!("The preview window is not available with several cameras, running headless");
+    }
+    if config.metrics.enabled {
+        metrics::spawn(SocketAddr::from(([0, 0, 0, 0], config.metrics.port)));
+    }
+    let mut shutdown = shutdown::listen();
+    let mut mqtt_rejected = mqtt.rejected();
+
+    let qos = mqtt::qos(config.broker.qos);
+    let publisher = Publisher::spawn(
+        mqtt.client.clone(),
+        mqtt.state(),
+        qos,
+        config.broker.publish_queue_capacity,
+        config.broker.dead_letter_capacity,
+        config.broker.dead_letter_max_bytes,
+    );
+
+    let mut cameras: Vec<CameraState> = config
+        .cameras
+        .iter()
+        .map(|entry| {
+            let camera_config = entry.camera_config(&config.camera);
+            let broker = BrokerConfig {
+                device_id: Some(entry.device_id.clone()),
+                ..config.broker.clone()
+            };
+            CameraState {
+                device_id: entry.device_id.clone(),
+                count_topic: Topics::new(&broker).count,
+                source: camera_config.source.label(),
+                frame: FrameSize {
+                    width: camera_config.frame_width as i32,
+                    height: camera_config.frame_height as i32,
+                },
+                count: 0,
+                last_published: None,
+            }
+        })
+        .collect();
+    let total_topic = format!("{}/total", config.broker.topic);
+    let mut total_published: Option<(usize, Instant)> = None;
+
+    // The sender is kept for restarted cameras
+    let (report_sender, mut reports) = mpsc::channel(REPORT_QUEUE_CAPACITY);
+    let mut running = CameraTasks::new();
+    for index in 0..config.cameras.len() {
+        spawn_camera(
+            &mut running,
+            index,
+            Duration::ZERO,
+            &config,
+            &report_sender,
+            &shutdown,
+        );
+    }
+
+    while !*shutdown.borrow() {
+        let report: Report = tokio::select! {
+            _ = shutdown.changed() => break,
+            Ok(()) = mqtt_rejected.changed() => {
+                if let Some(reason) = mqtt_rejected.borrow_and_update().clone() {
+                    return Err(AppError::MqttAuth(reason));
+                }
+                continue;
+            }
+            Some(report) = reports.recv() => report,
+            Some(joined) = running.join_next() => {
+                match joined {
+                    Ok((index, Err(e))) => {
+                        let device_id = &config.cameras[index].device_id;
+                        log::error!(
+                            "Camera {} failed, restarting it in {}s: {}",
+                            device_id,
+                            RESTART_DELAY.as_secs(),
+                            e
+                        );
+                        spawn_camera(&mut running, index, RESTART_DELAY, &config, &report_sender, &shutdown);
+                    }
+                    // End of a video file
+                    Ok((index, Ok(()))) => log::info!("Camera {} finished", config.cameras[index].device_id),
+                    Err(e) => log::error!("Camera task failed: {}", e),
+                }
+                if running.is_empty() {
+                    log::error!("All cameras have stopped");
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let camera = &mut cameras[report.camera];
+        camera.count = report.event.count;
+        metrics::CAMERA_PEOPLE_COUNT
+            .with_label_values(&[&camera.device_id])
+            .set(camera.count as i64);
+        camera.frame = report.event.frame;
+        if let Some(retain) = publish_due(&config.broker, &mut camera.last_published, camera.count) {
+            match build_payload(&report.event, config.broker.payload_format) {
+                Ok(payload) => publisher.send(&camera.count_topic, payload, retain),
+                Err(e) => log::warn!("Failed to serialize detection event: {}", e),
+            }
+        }
+        log::info!("People detected: {} ({})", camera.count, camera.device_id);
+
+        let total = total_event(&cameras);
+        metrics::PEOPLE_COUNT.set(total.count as i64);
+        if let Some(retain) = publish_due(&config.broker, &mut total_published, total.count) {
+            match total.to_payload(config.broker.payload_format) {
+                Ok(payload) => publisher.send(&total_topic, payload, retain),
+                Err(e) => log::warn!("Failed to serialize total count: {}", e),
+            }
+        }
+    }
+
+    // Report empty scenes so subscribers don't keep a stale count
+    publisher.stop(SHUTDOWN_TIMEOUT).await;
+    let retain = config.broker.retain || config.broker.heartbeat_retain;
+    let mut last_messages = Vec::new();
+    for camera in &mut cameras {
+        camera.count = 0;
+        let nobody = DetectionEvent::new(
+            &VectorOfRect::new(),
+            0,
+            camera.frame,
+            &[],
+            BTreeMap::new(),
+            camera.source.clone(),
+            false,
+        );
+        match build_payload(&nobody, config.broker.payload_format) {
+            Ok(payload) => last_messages.push(last_message(&camera.count_topic, payload, retain)),
+            Err(e) => log::warn!("Failed to serialize detection event: {}", e),
+        }
+    }
+    match total_event(&cameras).to_payload(config.broker.payload_format) {
+        Ok(payload) => last_messages.push(last_message(&total_topic, payload, retain)),
+        Err(e) => log::warn!("Failed to serialize total count: {}", e),
+    }
+    mqtt.close(&last_messages, qos, SHUTDOWN_TIMEOUT).await;
+
+    drop(reports);
+    // A camera that hangs in open or read mustn't keep the process from exiting
+    let stopped = time::timeout(SHUTDOWN_TIMEOUT, async {
+        while let Some(joined) = running.join_next().await {
+            match joined {
+                Ok((_, Ok(()))) => {}
+                Ok((index, Err(e))) => log::error!("Camera {} failed: {}", config.cameras[index].device_id, e),
+                Err(e) => log::error!("Camera task failed: {}", e),
+            }
+        }
+    })
+    .await;
+    if stopped.is_err() {
+        log::warn!(
+            "{} cameras didn't stop within {:?}, not waiting for them",
+            running.len(),
+            SHUTDOWN_TIMEOUT
+        );
+    }
+    Ok(())
+}
+
+// Starts camera `index` after `delay`, unless the process shuts down first
+fn spawn_camera(
+    running: &mut CameraTasks,
+    index: usize,
+    delay: Duration,
+    config: &Arc<Config>,
+    reports: &mpsc::Sender<Report>,
+    shutdown: &watch::Receiver<bool>,
+) {
+    let config = Arc::clone(config);
+    let reports = reports.clone();
+    let mut shutdown = shutdown.clone();
+    running.spawn(async move {
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = shutdown.changed() => return (index, Ok(())),
+        }
+        (index, run_camera(index, config, reports, shutdown).await)
+    });
+}
+
+// Opens one camera and runs detection on it until it stops. Errors only take
+// this camera down, the camera is reported as empty either way.
+async fn run_camera(
+    index: usize,
+    config: Arc<Config>,
+    reports: mpsc::Sender<Report>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<(), AppError> {
+    let entry = &config.cameras[index];
+    let camera_config = entry.camera_config(&config.camera);
+    let source = camera_config.source.label();
+    let cam = match Camera::open_with_retry(&camera_config).await {
+        Ok(cam) => cam,
+        Err(e) => {
+            report_empty(index, &camera_config, source, &reports).await;
+            return Err(AppError::Camera(e));
+        }
+    };
+
+    let (frame_sender, frames) = mpsc::channel(camera_config.frame_queue_capacity);
+    let (recycle_frames, recycled_frames) = mpsc::channel(camera_config.frame_queue_capacity + 1);
+    // Availability is shared by all cameras, a single stalled one isn't reported
+    let (healthy, _) = watch::channel(true);
+    // There's no command topic with several cameras
+    let (_, controls) = watch::channel(camera_config.controls());
+    let capture = camera::spawn_capture(
+        cam,
+        frame_sender,
+        recycled_frames,
+        Arc::default(),
+        healthy,
+        controls,
+        shutdown.clone(),
+    );
+    let detection = {
+        let config = Arc::clone(&config);
+        let reports = reports.clone();
+        let source = source.clone();
+        task::spawn_blocking(move || detect(index, &config, source, frames, &recycle_frames, &reports))
+    };
+
+    let detected = match detection.await {
+        Ok(result) => result.map_err(AppError::Detection),
+        Err(e) => Err(AppError::Detection(opencv::Error::new(
+            opencv::core::StsError,
+            format!("detection task failed: {}", e),
+        ))),
+    };
+    let captured = match capture.await {
+        Ok(result) => result.map_err(AppError::Camera),
+        Err(e) => Err(AppError::Camera(opencv::Error::new(
+            opencv::core::StsError,
+            format!("capture task failed: {}", e),
+        ))),
+    };
+
+    if !*shutdown.borrow() {
+        log::warn!("Camera {} stopped, reporting it as empty", entry.device_id);
+        report_empty(index, &camera_config, source, &reports).await;
+    }
+    detected.and(captured)
+}
+
+// Publishes a count of 0 for a camera that is gone
+async fn report_empty(camera: usize, camera_config: &CameraConfig, source: String, reports: &mpsc::Sender<Report>) {
+    let frame = FrameSize {
+        width: camera_config.frame_width as i32,
+        height: camera_config.frame_height as i32,
+    };
+    let event = DetectionEvent::new(&VectorOfRect::new(), 0, frame, &[], BTreeMap::new(), source, false);
+    let _ = reports.send(Report { camera, event }).await;
+}
+
+// Detection loop of one camera, runs on a blocking thread like capture
+fn detect(
+    camera: usize,
+    config: &Config,
+    source: String,
+    mut frames: mpsc::Receiver<CapturedFrame>,
+    recycle_frames: &mpsc::Sender<Mat>,
+    reports: &mpsc::Sender<Report>,
+) -> opencv::Result<()> {
+    let entry = &config.cameras[camera];
+    let zones = &entry.zones;
+    let frames_processed = metrics::CAMERA_FRAMES_PROCESSED.with_label_values(&[&entry.device_id]);
+    let detection_duration = metrics::CAMERA_DETECTION_DURATION_SECONDS.with_label_values(&[&entry.device_id]);
+    let mut detector = create_detector(config)?;
+    let mut tracker = CentroidTracker::new(&config.tracker);
+    let mut debouncer = Debouncer::new(config.detector.debounce_frames, 0);
+    let mut frames_seen: u64 = 0;
+
+    while let Some(CapturedFrame { image: frame, .. }) = frames.blocking_recv() {
+        let detect = frames_seen.is_multiple_of(config.detector.process_every_n);
+        frames_seen += 1;
+        if !detect {
+            let _ = recycle_frames.try_send(frame);
+            continue;
+        }
+
+        let started = std::time::Instant::now();
+        let boxes = zone::filter(zones, &detector.detect(&frame)?);
+        let latency = started.elapsed();
+        metrics::observe_detection(latency);
+        frames_processed.inc();
+        detection_duration.observe(latency.as_secs_f64());
+        let tracks = tracker.track(&boxes);
+        let count = debouncer.update(boxes.len());
+        let size = FrameSize {
+            width: frame.cols(),
+            height: frame.rows(),
+        };
+        // Capture reads into it again
+        let _ = recycle_frames.try_send(frame);
+        let mut event = DetectionEvent::new(
+            &boxes,
+            count,
+            size,
+            &tracks,
+            zone::counts(zones, &boxes),
+            source.clone(),
+            true,
+        );
+        event.unique_count = Some(tracker.unique_count());
+        // Gone once the process is shutting down
+        if reports.blocking_send(Report { camera, event }).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn total_event(cameras: &[CameraState]) -> TotalEvent {
+    TotalEvent::new(
+        cameras
+            .iter()
+            .map(|camera| (camera.device_id.clone(), camera.count))
+            .collect(),
+    )
+}
+
+// None skips the count, otherwise whether to retain it. Changes are always
+// published, unchanged counts as a heartbeat. Shared with the single camera loop.
+pub(crate) fn publish_due(broker: &BrokerConfig, last_published: &mut Option<(usize, Instant)>, count: usize) -> Option<bool> {
+    let publish_interval = Duration::from_secs(broker.publish_interval_secs);
+    let retain = match *last_published {
+        _ if broker.publish_every_frame => Some(broker.retain),
+        Some((last, at)) if last == count => {
+            let heartbeat = !publish_interval.is_zero() && at.elapsed() >= publish_interval;
+            heartbeat.then_some(broker.retain || broker.heartbeat_retain)
+        }
+        _ => Some(broker.retain),
+    };
+    if retain.is_some() {
+        *last_published = Some((count, Instant::now()));
+    }
+    retain
+}
+
+fn last_message(topic: &str, payload: String, retain: bool) -> Message {
+    Message {
+        topic: topic.to_string(),
+        payload: payload.into_bytes(),
+        retain,
+        qos: None,
+    }
+}
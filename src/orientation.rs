@@ -0,0 +1,41 @@
+use opencv::core::{self, Mat};
+
+use crate::config::{CameraConfig, Flip};
+
+// Turns the frames of a camera mounted sideways or upside down before
+// anything else sees them, HOG hardly finds people standing on their heads.
+// Boxes, overlays and recordings all use the turned frame.
+pub struct Orientation {
+    rotate: Option<i32>,
+    flip: Option<i32>,
+}
+
+impl Orientation {
+    // None when the frames are used as captured, saving a copy per frame
+    pub fn new(config: &CameraConfig) -> Option<Self> {
+        let rotate = match config.rotate {
+            90 => Some(core::ROTATE_90_CLOCKWISE),
+            180 => Some(core::ROTATE_180),
+            270 => Some(core::ROTATE_90_COUNTERCLOCKWISE),
+            _ => None,
+        };
+        let flip = config.flip.map(|flip| match flip {
+            // Around the vertical axis, i.e. mirrored left to right
+            Flip::Horizontal => 1,
+            Flip::Vertical => 0,
+        });
+        (rotate.is_some() || flip.is_some()).then_some(Orientation { rotate, flip })
+    }
+
+    pub fn apply(&self, frame: &mut Mat) -> opencv::Result<()> {
+        if let Some(code) = self.rotate {
+            let captured = std::mem::take(frame);
+            core::rotate(&captured, frame, code)?;
+        }
+        if let Some(code) = self.flip {
+            let captured = std::mem::take(frame);
+            core::flip(&captured, frame, code)?;
+        }
+        Ok(())
+    }
+}
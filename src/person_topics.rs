@@ -0,0 +1,76 @@
+use rumqttc::QoS;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::event::BoundingBox;
+use crate::mqtt::Topics;
+use crate::publisher::Publisher;
+use crate::tracker::TrackedPerson;
+
+// A person not seen for this long gets the `null` tombstone on their topic
+const PERSON_EXPIRY: Duration = Duration::from_secs(2);
+const TOMBSTONE: &str = "null";
+
+#[derive(Debug, Serialize)]
+struct PersonPayload {
+    #[serde(flatten)]
+    bbox: BoundingBox,
+    // The detectors only report boxes so far, always null
+    confidence: Option<f32>,
+}
+
+// Publishes every tracked person to `<prefix>/person/<track_id>` for
+// consumers that need locations rather than a count. Positions go out at
+// QoS 0, they are superseded by the next pass anyway.
+pub struct PersonTopics {
+    person_prefix: String,
+    // Integer count for these consumers, unless the count topic is that already
+    count_topic: Option<String>,
+    last_seen: HashMap<u32, Instant>,
+}
+
+impl PersonTopics {
+    pub fn new(topics: &Topics) -> Self {
+        let count_topic = format!("{}/count", topics.prefix);
+        PersonTopics {
+            person_prefix: format!("{}/person", topics.prefix),
+            count_topic: (count_topic != topics.count).then_some(count_topic),
+            last_seen: HashMap::new(),
+        }
+    }
+
+    pub fn publish(&mut self, publisher: &Publisher, tracks: &[TrackedPerson]) {
+        let now = Instant::now();
+        for track in tracks {
+            let payload = PersonPayload {
+                bbox: track.bbox.into(),
+                confidence: None,
+            };
+            match serde_json::to_string(&payload) {
+                Ok(json) => publisher.send_with_qos(&self.topic(track.id), json, false, QoS::AtMostOnce),
+                Err(e) => log::warn!("Failed to serialize person {}: {}", track.id, e),
+            }
+            self.last_seen.insert(track.id, now);
+        }
+        if let Some(count_topic) = &self.count_topic {
+            publisher.send_with_qos(count_topic, tracks.len().to_string(), false, QoS::AtMostOnce);
+        }
+
+        let expired: Vec<u32> = self
+            .last_seen
+            .iter()
+            .filter(|(_, seen)| now.duration_since(**seen) >= PERSON_EXPIRY)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.last_seen.remove(&id);
+            // Sent with the configured QoS, a lost tombstone leaves a ghost behind
+            publisher.send(&self.topic(id), TOMBSTONE, false);
+        }
+    }
+
+    fn topic(&self, id: u32) -> String {
+        format!("{}/{}", self.person_prefix, id)
+    }
+}
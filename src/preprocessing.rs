@@ -0,0 +1,325 @@
+use opencv::{
+    core::{self, Mat, Point2f, Ptr, Rect, Scalar, Size},
+    imgproc::{self, CLAHE},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+// Channels of the BGR frames coming from the camera
+const CAMERA_CHANNELS: i32 = 3;
+
+// One stage applied to a frame before detection, written in the config as
+// e.g. `{ step = "gaussian_blur", ksize = 5, sigma = 1.0 }`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case", deny_unknown_fields)]
+pub enum PreprocessStep {
+    GrayScale,
+    // `ksize` must be odd, a `sigma` of 0 derives it from the kernel size
+    GaussianBlur { ksize: u32, sigma: f64 },
+    // Contrast limited adaptive histogram equalization, needs a gray image
+    #[serde(rename = "clahe")]
+    Clahe { clip_limit: f64, tile_grid_size: u32 },
+    Resize { width: i32, height: i32 },
+    // Counter-clockwise in degrees, multiples of 90 swap width and height
+    Rotate { angle: f64 },
+}
+
+// Runs the steps on every detection pass. The intermediate images are kept
+// between passes, at a fixed camera resolution OpenCV writes into the same
+// buffers every time instead of allocating per frame.
+pub struct Preprocessor {
+    steps: Vec<PreprocessStep>,
+    // One per step, None for steps other than CLAHE
+    clahe: Vec<Option<Ptr<dyn CLAHE>>>,
+    // The last step's output and a buffer to write the next one into
+    current: Mat,
+    next: Mat,
+    // Input size of every step on the last `apply`
+    sizes: Vec<Size>,
+}
+
+impl Preprocessor {
+    pub fn new(steps: Vec<PreprocessStep>) -> opencv::Result<Self> {
+        let clahe = steps
+            .iter()
+            .map(|step| match step {
+                PreprocessStep::Clahe {
+                    clip_limit,
+                    tile_grid_size,
+                } => {
+                    let tiles = *tile_grid_size as i32;
+                    imgproc::create_clahe(*clip_limit, Size::new(tiles, tiles)).map(Some)
+                }
+                _ => Ok(None),
+            })
+            .collect::<opencv::Result<_>>()?;
+        Ok(Preprocessor {
+            steps,
+            clahe,
+            current: Mat::default(),
+            next: Mat::default(),
+            sizes: Vec::new(),
+        })
+    }
+
+    // Without steps that is the frame itself, nothing is copied
+    pub fn apply<'a>(&'a mut self, frame: &'a Mat) -> opencv::Result<&'a Mat> {
+        let Preprocessor {
+            steps,
+            clahe,
+            current,
+            next,
+            sizes,
+        } = self;
+        if steps.is_empty() {
+            return Ok(frame);
+        }
+        sizes.clear();
+        for (i, (step, clahe)) in steps.iter().zip(clahe.iter_mut()).enumerate() {
+            let image: &Mat = if i == 0 { frame } else { current };
+            sizes.push(image.size()?);
+            match step {
+                PreprocessStep::GrayScale => {
+                    imgproc::cvt_color(image, next, imgproc::COLOR_BGR2GRAY, 0)?;
+                }
+                PreprocessStep::GaussianBlur { ksize, sigma } => {
+                    let ksize = *ksize as i32;
+                    imgproc::gaussian_blur(
+                        image,
+                        next,
+                        Size::new(ksize, ksize),
+                        *sigma,
+                        *sigma,
+                        core::BORDER_DEFAULT,
+                    )?;
+                }
+                PreprocessStep::Clahe { .. } => {
+                    if let Some(clahe) = clahe {
+                        clahe.apply(image, next)?;
+                    }
+                }
+                PreprocessStep::Resize { width, height } => {
+                    imgproc::resize(
+                        image,
+                        next,
+                        Size::new(*width, *height),
+                        0.0,
+                        0.0,
+                        imgproc::INTER_LINEAR,
+                    )?;
+                }
+                PreprocessStep::Rotate { angle } => rotate(image, next, *angle)?,
+            }
+            std::mem::swap(current, next);
+        }
+        Ok(current)
+    }
+
+    // Maps boxes found in the output of the last `apply` back onto the frame
+    // it was given. Exact for resizing and quarter turns, other angles give
+    // the upright box around the turned one.
+    pub fn map_back(&self, boxes: &[Rect]) -> Vec<Rect> {
+        boxes
+            .iter()
+            .map(|rect| map_rect(&self.steps, &self.sizes, rect))
+            .collect()
+    }
+}
+
+fn map_rect(steps: &[PreprocessStep], sizes: &[Size], rect: &Rect) -> Rect {
+    let (left, top) = (rect.x as f64, rect.y as f64);
+    let (right, bottom) = ((rect.x + rect.width) as f64, (rect.y + rect.height) as f64);
+    let mut corners = [(left, top), (right, top), (left, bottom), (right, bottom)];
+    for (step, input) in steps.iter().zip(sizes).rev() {
+        for corner in &mut corners {
+            *corner = to_input(step, *input, *corner);
+        }
+    }
+    let (mut left, mut top) = (f64::INFINITY, f64::INFINITY);
+    let (mut right, mut bottom) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+    for (x, y) in corners {
+        left = left.min(x);
+        top = top.min(y);
+        right = right.max(x);
+        bottom = bottom.max(y);
+    }
+    let (left, top) = (left.round() as i32, top.round() as i32);
+    Rect::new(left, top, right.round() as i32 - left, bottom.round() as i32 - top)
+}
+
+// Where a point of a step's output lies in its input of `input` pixels
+fn to_input(step: &PreprocessStep, input: Size, (x, y): (f64, f64)) -> (f64, f64) {
+    let (width, height) = (input.width as f64, input.height as f64);
+    match step {
+        PreprocessStep::Resize {
+            width: resized_width,
+            height: resized_height,
+        } => (
+            x * width / *resized_width as f64,
+            y * height / *resized_height as f64,
+        ),
+        PreprocessStep::Rotate { angle } => {
+            let angle = angle.rem_euclid(360.0);
+            if angle == 90.0 {
+                (width - y, x)
+            } else if angle == 180.0 {
+                (width - x, height - y)
+            } else if angle == 270.0 {
+                (y, height - x)
+            } else {
+                // Turned back around the centre, the inverse of the matrix
+                // `get_rotation_matrix_2d` builds with y pointing down
+                let (sin, cos) = angle.to_radians().sin_cos();
+                let (dx, dy) = (x - width / 2.0, y - height / 2.0);
+                (width / 2.0 + cos * dx - sin * dy, height / 2.0 + sin * dx + cos * dy)
+            }
+        }
+        _ => (x, y),
+    }
+}
+
+fn rotate(image: &Mat, output: &mut Mat, angle: f64) -> opencv::Result<()> {
+    let angle = angle.rem_euclid(360.0);
+    if angle == 0.0 {
+        image.copy_to(output)?;
+        return Ok(());
+    }
+    // Lossless and keeps the whole picture for cameras mounted sideways
+    let quarter_turn = if angle == 90.0 {
+        Some(core::ROTATE_90_COUNTERCLOCKWISE)
+    } else if angle == 180.0 {
+        Some(core::ROTATE_180)
+    } else if angle == 270.0 {
+        Some(core::ROTATE_90_CLOCKWISE)
+    } else {
+        None
+    };
+    if let Some(code) = quarter_turn {
+        return core::rotate(image, output, code);
+    }
+
+    let size = image.size()?;
+    let center = Point2f::new(size.width as f32 / 2.0, size.height as f32 / 2.0);
+    let matrix = imgproc::get_rotation_matrix_2d(center, angle, 1.0)?;
+    imgproc::warp_affine(
+        image,
+        output,
+        &matrix,
+        size,
+        imgproc::INTER_LINEAR,
+        core::BORDER_CONSTANT,
+        Scalar::default(),
+    )
+}
+
+// Checks the steps against each other without a frame, returning the number
+// of channels the pipeline ends with for a BGR camera frame
+pub fn output_channels(steps: &[PreprocessStep]) -> Result<i32, String> {
+    let mut channels = CAMERA_CHANNELS;
+    for (i, step) in steps.iter().enumerate() {
+        match step {
+            PreprocessStep::GrayScale => {
+                if channels != CAMERA_CHANNELS {
+                    return Err(format!("step {} (gray_scale) needs a color image", i));
+                }
+                channels = 1;
+            }
+            PreprocessStep::GaussianBlur { ksize, sigma } => {
+                if ksize % 2 == 0 {
+                    return Err(format!("step {} (gaussian_blur) ksize must be odd, got {}", i, ksize));
+                }
+                if *sigma < 0.0 {
+                    return Err(format!("step {} (gaussian_blur) sigma must not be negative", i));
+                }
+            }
+            PreprocessStep::Clahe {
+                clip_limit,
+                tile_grid_size,
+            } => {
+                if channels != 1 {
+                    return Err(format!("step {} (clahe) needs a gray image, add gray_scale before it", i));
+                }
+                if *clip_limit <= 0.0 || *tile_grid_size == 0 {
+                    return Err(format!(
+                        "step {} (clahe) needs a positive clip_limit and tile_grid_size",
+                        i
+                    ));
+                }
+            }
+            PreprocessStep::Resize { width, height } => {
+                if *width <= 0 || *height <= 0 {
+                    return Err(format!(
+                        "step {} (resize) needs a positive size, got {}x{}",
+                        i, width, height
+                    ));
+                }
+            }
+            PreprocessStep::Rotate { .. } => {}
+        }
+    }
+    Ok(channels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotate(angle: f64) -> PreprocessStep {
+        PreprocessStep::Rotate { angle }
+    }
+
+    const FRAME: Size = Size {
+        width: 640,
+        height: 480,
+    };
+
+    #[test]
+    fn quarter_turns_map_back_exactly() {
+        // In the top left corner of the frame before the turn
+        let person = Rect::new(0, 0, 64, 128);
+        // Counter-clockwise, the frame's top left corner ends up bottom left
+        assert_eq!(
+            map_rect(&[rotate(90.0)], &[FRAME], &Rect::new(0, 576, 128, 64)),
+            person
+        );
+        assert_eq!(
+            map_rect(&[rotate(180.0)], &[FRAME], &Rect::new(576, 352, 64, 128)),
+            person
+        );
+        assert_eq!(
+            map_rect(&[rotate(270.0)], &[FRAME], &Rect::new(352, 0, 128, 64)),
+            person
+        );
+        assert_eq!(
+            map_rect(&[rotate(-90.0)], &[FRAME], &Rect::new(352, 0, 128, 64)),
+            person
+        );
+    }
+
+    #[test]
+    fn resize_and_turn_map_back_in_reverse_order() {
+        let steps = [
+            PreprocessStep::GrayScale,
+            PreprocessStep::Resize {
+                width: 320,
+                height: 240,
+            },
+            rotate(90.0),
+        ];
+        let sizes = [FRAME, FRAME, Size::new(320, 240)];
+        // 240 wide and 320 high after the turn
+        assert_eq!(
+            map_rect(&steps, &sizes, &Rect::new(10, 256, 64, 32)),
+            Rect::new(64, 20, 64, 128)
+        );
+    }
+
+    #[test]
+    fn other_angles_give_the_box_around_the_turned_one() {
+        let centre = Rect::new(310, 230, 20, 20);
+        let mapped = map_rect(&[rotate(45.0)], &[FRAME], &centre);
+        // Rotated around the frame's centre, so it stays there, but grows by √2
+        assert_eq!(mapped, Rect::new(306, 226, 28, 28));
+        assert_eq!(map_rect(&[rotate(0.0)], &[FRAME], &centre), centre);
+    }
+}
@@ -0,0 +1,254 @@
+use rumqttc::{AsyncClient, QoS};
+use std::collections::VecDeque;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration, Instant};
+
+use crate::metrics;
+
+// How often the number of dropped messages is logged
+const DROPPED_LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+    // None publishes at the configured QoS
+    pub qos: Option<QoS>,
+}
+
+// Single long-lived task publishing everything the detection loop produces.
+// The queue is a broadcast channel because it already drops the oldest
+// message when full, which is what we want since counts supersede each other.
+pub struct Publisher {
+    queue: broadcast::Sender<Message>,
+    task: JoinHandle<()>,
+}
+
+impl Publisher {
+    pub fn spawn(
+        client: AsyncClient,
+        connected: watch::Receiver<bool>,
+        qos: QoS,
+        capacity: usize,
+        dead_letter_capacity: usize,
+        dead_letter_max_bytes: usize,
+    ) -> Self {
+        let (queue, messages) = broadcast::channel(capacity);
+        let dead_letters = DeadLetters::new(dead_letter_capacity, dead_letter_max_bytes);
+        Publisher {
+            queue,
+            task: tokio::spawn(run(client, connected, qos, messages, dead_letters)),
+        }
+    }
+
+    // Never blocks, the oldest queued message is dropped when the queue is full
+    pub fn send(&self, topic: &str, payload: impl Into<Vec<u8>>, retain: bool) {
+        let _ = self.queue.send(Message {
+            topic: topic.to_string(),
+            payload: payload.into(),
+            retain,
+            qos: None,
+        });
+    }
+
+    // Like `send`, for messages that need a different QoS than the rest
+    pub fn send_with_qos(&self, topic: &str, payload: impl Into<Vec<u8>>, retain: bool, qos: QoS) {
+        let _ = self.queue.send(Message {
+            topic: topic.to_string(),
+            payload: payload.into(),
+            retain,
+            qos: Some(qos),
+        });
+    }
+
+    // Publishes what is still queued, abandoning the rest after `timeout`.
+    // The final event is sent by `mqtt::Connection::close`.
+    pub async fn stop(self, timeout: Duration) {
+        drop(self.queue);
+        let mut task = self.task;
+        if time::timeout(timeout, &mut task).await.is_err() {
+            log::warn!("Queued MQTT messages weren't published within {:?}, dropping them", timeout);
+            task.abort();
+        }
+    }
+}
+
+// Messages produced while disconnected, published in order before any live
+// ones once the broker is back. The oldest go first beyond either limit.
+struct DeadLetters {
+    messages: VecDeque<Message>,
+    bytes: usize,
+    capacity: usize,
+    max_bytes: usize,
+}
+
+impl DeadLetters {
+    fn new(capacity: usize, max_bytes: usize) -> Self {
+        DeadLetters {
+            messages: VecDeque::new(),
+            bytes: 0,
+            capacity,
+            max_bytes,
+        }
+    }
+
+    // Returns how many messages were dropped, 1 for the new one when it
+    // can't be kept at all
+    fn push(&mut self, message: Message) -> u64 {
+        let size = size(&message);
+        if self.capacity == 0 || size > self.max_bytes {
+            return 1;
+        }
+        let mut dropped = 0;
+        while self.messages.len() == self.capacity || self.bytes + size > self.max_bytes {
+            self.pop();
+            dropped += 1;
+        }
+        self.bytes += size;
+        self.messages.push_back(message);
+        dropped
+    }
+
+    fn pop(&mut self) -> Option<Message> {
+        let message = self.messages.pop_front()?;
+        self.bytes -= size(&message);
+        Some(message)
+    }
+
+    fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+fn size(message: &Message) -> usize {
+    message.topic.len() + message.payload.len()
+}
+
+async fn run(
+    client: AsyncClient,
+    mut connected: watch::Receiver<bool>,
+    qos: QoS,
+    mut messages: broadcast::Receiver<Message>,
+    mut dead_letters: DeadLetters,
+) {
+    let mut dropped: u64 = 0;
+    let mut interval = time::interval_at(Instant::now() + DROPPED_LOG_INTERVAL, DROPPED_LOG_INTERVAL);
+    loop {
+        let message = tokio::select! {
+            received = messages.recv() => match received {
+                Ok(message) => message,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    dropped += skipped;
+                    continue;
+                }
+                // Stopping, the queue is drained
+                Err(broadcast::error::RecvError::Closed) => {
+                    if *connected.borrow() {
+                        while let Some(message) = dead_letters.pop() {
+                            publish(&client, qos, message).await;
+                        }
+                    }
+                    return;
+                }
+            },
+            Ok(()) = connected.changed() => {
+                if *connected.borrow_and_update() && !dead_letters.is_empty() {
+                    log::info!("Publishing {} messages buffered during the outage", dead_letters.len());
+                    // Stop early if the connection drops again while draining
+                    while *connected.borrow() {
+                        let Some(message) = dead_letters.pop() else {
+                            break;
+                        };
+                        publish(&client, qos, message).await;
+                    }
+                }
+                continue;
+            }
+            _ = interval.tick() => {
+                if dropped > 0 {
+                    log::warn!("Dropped {} MQTT messages in the last {:?}", dropped, DROPPED_LOG_INTERVAL);
+                    dropped = 0;
+                }
+                continue;
+            }
+        };
+
+        // Publishing while disconnected would only fill the client's request
+        // channel with stale counts
+        if !*connected.borrow() {
+            dropped += dead_letters.push(message);
+            continue;
+        }
+        publish(&client, qos, message).await;
+    }
+}
+
+async fn publish(client: &AsyncClient, qos: QoS, message: Message) {
+    if let Err(e) = client
+        .publish(message.topic, message.qos.unwrap_or(qos), message.retain, message.payload)
+        .await
+    {
+        log::warn!("Failed to publish message: {}", e);
+        metrics::MQTT_PUBLISH_ERRORS.inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(topic: &str, payload_bytes: usize) -> Message {
+        Message {
+            topic: topic.to_string(),
+            payload: vec![0; payload_bytes],
+            retain: false,
+            qos: None,
+        }
+    }
+
+    fn topics(dead_letters: &mut DeadLetters) -> Vec<String> {
+        std::iter::from_fn(|| dead_letters.pop()).map(|message| message.topic).collect()
+    }
+
+    #[test]
+    fn oldest_message_goes_when_the_count_is_reached() {
+        let mut dead_letters = DeadLetters::new(2, 1000);
+        assert_eq!(dead_letters.push(message("a", 1)), 0);
+        assert_eq!(dead_letters.push(message("b", 1)), 0);
+        assert_eq!(dead_letters.push(message("c", 1)), 1);
+        assert_eq!(topics(&mut dead_letters), ["b", "c"]);
+    }
+
+    #[test]
+    fn oldest_messages_go_when_the_bytes_are_reached() {
+        let mut dead_letters = DeadLetters::new(10, 100);
+        dead_letters.push(message("a", 39));
+        dead_letters.push(message("b", 39));
+        // Needs the room of both older ones
+        assert_eq!(dead_letters.push(message("c", 79)), 2);
+        assert_eq!(dead_letters.bytes, 80);
+        assert_eq!(topics(&mut dead_letters), ["c"]);
+        assert_eq!(dead_letters.bytes, 0);
+    }
+
+    #[test]
+    fn message_larger_than_the_bytes_is_not_kept() {
+        let mut dead_letters = DeadLetters::new(10, 100);
+        dead_letters.push(message("a", 10));
+        assert_eq!(dead_letters.push(message("snapshot", 200)), 1);
+        assert_eq!(topics(&mut dead_letters), ["a"]);
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let mut dead_letters = DeadLetters::new(0, 100);
+        assert_eq!(dead_letters.push(message("a", 1)), 1);
+        assert!(dead_letters.is_empty());
+    }
+}
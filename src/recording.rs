@@ -0,0 +1,114 @@
+use chrono::Local;
+use opencv::{
+    core::{self, Mat, Size},
+    prelude::*,
+    videoio::VideoWriter,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::camera::fourcc_code;
+use crate::config::RecordingConfig;
+
+// Writes annotated frames to an .avi file while people are in view, as an
+// evidence trail for security deployments. With `path` set everything goes
+// into that one file instead, see `--record`.
+pub struct FrameRecorder {
+    output_dir: PathBuf,
+    path: Option<PathBuf>,
+    skip_empty: bool,
+    fourcc: String,
+    fps: f64,
+    threshold: usize,
+    cooldown: Duration,
+    writer: Option<VideoWriter>,
+    // When the count last reached the threshold, recording stops once the
+    // scene has been empty for the cooldown after that
+    last_triggered: Instant,
+}
+
+impl FrameRecorder {
+    pub fn new(config: &RecordingConfig) -> std::io::Result<Self> {
+        fs::create_dir_all(config.dir())?;
+        Ok(FrameRecorder {
+            output_dir: config.output_dir.clone(),
+            path: config.path.clone(),
+            skip_empty: config.skip_empty,
+            fourcc: config.fourcc.clone(),
+            fps: config.fps,
+            threshold: config.record_threshold,
+            cooldown: Duration::from_secs(config.record_cooldown_secs),
+            writer: None,
+            last_triggered: Instant::now(),
+        })
+    }
+
+    pub fn update(&mut self, frame: &Mat, people_count: usize) -> opencv::Result<()> {
+        if let Some(path) = &self.path {
+            if self.writer.is_none() {
+                let path = path.clone();
+                self.start(frame, &path)?;
+            }
+            if people_count > 0 || !self.skip_empty {
+                if let Some(writer) = &mut self.writer {
+                    writer.write(frame)?;
+                }
+            }
+            return Ok(());
+        }
+
+        if people_count >= self.threshold {
+            self.last_triggered = Instant::now();
+            if self.writer.is_none() {
+                let path = self
+                    .output_dir
+                    .join(format!("{}.avi", Local::now().format("%Y%m%d_%H%M%S")));
+                self.start(frame, &path)?;
+            }
+        } else if people_count == 0
+            && self.writer.is_some()
+            && self.last_triggered.elapsed() >= self.cooldown
+        {
+            return self.stop();
+        }
+
+        if let Some(writer) = &mut self.writer {
+            writer.write(frame)?;
+        }
+        Ok(())
+    }
+
+    // Finishes the current file, also called on shutdown so the file is playable
+    pub fn stop(&mut self) -> opencv::Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.release()?;
+            log::info!("Stopped recording");
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, frame: &Mat, path: &Path) -> opencv::Result<()> {
+        let path_str = path.to_string_lossy();
+        let writer = VideoWriter::new(
+            &path_str,
+            fourcc_code(&self.fourcc)?,
+            self.fps,
+            Size::new(frame.cols(), frame.rows()),
+            true,
+        )?;
+        // OpenCV only reports an unsupported codec like this
+        if !writer.is_opened()? {
+            return Err(opencv::Error::new(
+                core::StsError,
+                format!(
+                    "Unable to open {} for recording, check that this OpenCV build supports the {} codec",
+                    path_str, self.fourcc
+                ),
+            ));
+        }
+        log::info!("Recording to {}", path_str);
+        self.writer = Some(writer);
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+use chrono::{DateTime, Utc};
+use hyper::client::conn;
+use hyper::{header, Body, Method, Request, StatusCode, Uri};
+use ring::{digest, hmac};
+use rustls::{ClientConfig, ServerName};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_rustls::TlsConnector;
+
+use crate::config::{ConfigError, S3UploadConfig};
+use crate::tls;
+
+// Snapshots waiting for upload, more are only kept on disk
+const QUEUE_CAPACITY: usize = 16;
+// A hanging endpoint counts as a failed upload
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// Headers covered by the signature, sorted and lowercase
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+// Copies saved snapshots and their .json files to an S3-compatible bucket
+// with one signed PUT per file (AWS Signature Version 4). Uploads run on a
+// task of their own, failures are logged and the files stay on disk.
+pub struct S3Uploader {
+    files: mpsc::Sender<PathBuf>,
+    uploader: JoinHandle<()>,
+}
+
+impl S3Uploader {
+    // `config` was checked by `Config::validate`
+    pub fn spawn(config: &S3UploadConfig) -> Result<Self, ConfigError> {
+        let invalid = |reason: String| ConfigError::Invalid(format!("s3_upload: {}", reason));
+        let endpoint: Uri = config
+            .endpoint
+            .parse()
+            .map_err(|e| invalid(format!("invalid endpoint: {}", e)))?;
+        let https = endpoint.scheme_str() == Some("https");
+        let (Some(host), Some(authority)) = (endpoint.host(), endpoint.authority()) else {
+            return Err(invalid(format!("endpoint {} has no host", config.endpoint)));
+        };
+        let tls = if https {
+            Some(TlsConnector::from(Arc::new(tls_config().map_err(invalid)?)))
+        } else {
+            None
+        };
+
+        let bucket = Bucket {
+            host: host.to_string(),
+            port: endpoint.port_u16().unwrap_or(if https { 443 } else { 80 }),
+            authority: authority.as_str().to_string(),
+            base_path: endpoint.path().trim_end_matches('/').to_string(),
+            tls,
+            region: config.region.clone(),
+            name: config.bucket.clone(),
+            prefix: config.prefix.clone(),
+            access_key_id: config.access_key_id.clone().unwrap_or_default(),
+            secret_access_key: config.secret_access_key.clone().unwrap_or_default(),
+        };
+        let (files, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        Ok(S3Uploader {
+            files,
+            uploader: tokio::spawn(bucket.run(receiver)),
+        })
+    }
+
+    // Never blocks, the snapshot is only kept on disk when the uploader is that far behind
+    pub fn upload(&self, path: &Path) {
+        if self.files.try_send(path.to_path_buf()).is_err() {
+            log::warn!("S3 upload is behind, not uploading {}", path.display());
+        }
+    }
+
+    // Finishes the uploads still queued
+    pub async fn close(self) {
+        drop(self.files);
+        if let Err(e) = self.uploader.await {
+            log::error!("S3 uploader failed: {}", e);
+        }
+    }
+}
+
+struct Bucket {
+    host: String,
+    port: u16,
+    // Host header, with the port when the endpoint names one
+    authority: String,
+    // Path of the endpoint without the trailing slash, usually empty
+    base_path: String,
+    // Only for https:// endpoints
+    tls: Option<TlsConnector>,
+    region: String,
+    name: String,
+    prefix: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+impl Bucket {
+    async fn run(self, mut files: mpsc::Receiver<PathBuf>) {
+        while let Some(path) = files.recv().await {
+            match tokio::fs::read(&path).await {
+                Ok(body) => self.put(&path, body, "image/jpeg").await,
+                Err(e) => {
+                    log::warn!("Unable to read {} for upload: {}", path.display(), e);
+                    continue;
+                }
+            }
+            // Only there with `snapshots.metadata`
+            let sidecar = path.with_extension("json");
+            match tokio::fs::read(&sidecar).await {
+                Ok(body) => self.put(&sidecar, body, "application/json").await,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => log::warn!("Unable to read {} for upload: {}", sidecar.display(), e),
+            }
+        }
+    }
+
+    async fn put(&self, path: &Path, body: Vec<u8>, content_type: &str) {
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            return;
+        };
+        let key = format!("{}{}", self.prefix, name);
+        let request = match self.signed_request(&key, body, content_type, Utc::now()) {
+            Ok(request) => request,
+            Err(e) => {
+                log::warn!("Failed to build the S3 request for {}: {}", key, e);
+                return;
+            }
+        };
+        match time::timeout(REQUEST_TIMEOUT, self.send(request)).await {
+            Ok(Ok(status)) if status.is_success() => {
+                log::info!("Uploaded {} to s3://{}/{}", path.display(), self.name, key);
+            }
+            // E.g. 403 for wrong credentials or a clock that is off by more than 15 minutes
+            Ok(Ok(status)) => log::warn!("S3 refused {} with {}", key, status),
+            Ok(Err(e)) => log::warn!("Failed to upload {}: {}", path.display(), e),
+            Err(_) => log::warn!("S3 didn't answer in time, {} not uploaded", path.display()),
+        }
+    }
+
+    fn signed_request(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        content_type: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Request<Body>, hyper::http::Error> {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let path = format!("{}/{}/{}", self.base_path, uri_encode(&self.name, false), uri_encode(key, true));
+        let payload_hash = hex(digest::digest(&digest::SHA256, &body).as_ref());
+        let authorization = self.authorization(&path, &payload_hash, &amz_date);
+        Request::builder()
+            .method(Method::PUT)
+            .uri(path)
+            .header(header::HOST, &self.authority)
+            .header(header::CONTENT_TYPE, content_type)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header(header::AUTHORIZATION, authorization)
+            .body(Body::from(body))
+    }
+
+    // `path` is already encoded, `amz_date` like 20240115T103000Z
+    fn authorization(&self, path: &str, payload_hash: &str, amz_date: &str) -> String {
+        let date = &amz_date[..8];
+        let canonical_request = format!(
+            "PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            path, self.authority, payload_hash, amz_date, SIGNED_HEADERS, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+        );
+        let key = hmac::Key::new(
+            hmac::HMAC_SHA256,
+            &signing_key(&self.secret_access_key, date, &self.region, "s3"),
+        );
+        let signature = hex(hmac::sign(&key, string_to_sign.as_bytes()).as_ref());
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id, scope, SIGNED_HEADERS, signature
+        )
+    }
+
+    // A connection per file, snapshots are far apart
+    async fn send(&self, request: Request<Body>) -> io::Result<StatusCode> {
+        let tcp = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        match &self.tls {
+            Some(tls) => {
+                let name = ServerName::try_from(self.host.as_str()).map_err(io::Error::other)?;
+                send_over(tls.connect(name, tcp).await?, request).await
+            }
+            None => send_over(tcp, request).await,
+        }
+    }
+}
+
+async fn send_over<T>(io: T, request: Request<Body>) -> io::Result<StatusCode>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = conn::handshake(io).await.map_err(io::Error::other)?;
+    // Drives the connection, it ends once the response is in and dropped
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            log::debug!("S3 connection closed: {}", e);
+        }
+    });
+    let response = sender.send_request(request).await.map_err(io::Error::other)?;
+    Ok(response.status())
+}
+
+// Verifies the endpoint against the CA certificates of the system
+fn tls_config() -> Result<ClientConfig, String> {
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(tls::native_roots()?)
+        .with_no_client_auth())
+}
+
+// kSigning of Signature Version 4, derived from the secret for one day
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let mut key = format!("AWS4{}", secret).into_bytes();
+    for part in [date, region, service, "aws4_request"] {
+        key = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &key), part.as_bytes())
+            .as_ref()
+            .to_vec();
+    }
+    key
+}
+
+// S3 encodes everything but the unreserved characters, the slashes of a key stay
+fn uri_encode(value: &str, keep_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            b'/' if keep_slash => "/".to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signing_key_matches_the_aws_example() {
+        // From the AWS documentation on deriving a Signature Version 4 signing key
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam");
+        assert_eq!(
+            hex(&key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn keys_are_encoded_except_for_slashes() {
+        assert_eq!(
+            uri_encode("front door/snapshot_1+2.jpg", true),
+            "front%20door/snapshot_1%2B2.jpg"
+        );
+        assert_eq!(uri_encode("a/b", false), "a%2Fb");
+    }
+}
@@ -0,0 +1,197 @@
+use chrono::{Local, SecondsFormat, Utc};
+use opencv::{
+    core::{Size, Vector},
+    imgcodecs, imgproc,
+    prelude::*,
+    types::VectorOfRect,
+};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::config::{SnapshotPublishConfig, SnapshotsConfig};
+use crate::event::BoundingBox;
+
+// Writes the frame as `<dir>/<timestamp>.jpg`, creating the directory if needed
+pub fn save(dir: &Path, frame: &Mat) -> opencv::Result<PathBuf> {
+    create_dir(dir)?;
+    let path = dir.join(format!("{}.jpg", Local::now().format("%Y%m%d_%H%M%S%.3f")));
+    write(&path, frame, &Vector::new())?;
+    Ok(path)
+}
+
+fn create_dir(dir: &Path) -> opencv::Result<()> {
+    fs::create_dir_all(dir).map_err(|e| {
+        opencv::Error::new(
+            opencv::core::StsError,
+            format!("Unable to create {}: {}", dir.display(), e),
+        )
+    })
+}
+
+fn write(path: &Path, frame: &Mat, params: &Vector<i32>) -> opencv::Result<()> {
+    if !imgcodecs::imwrite(&path.to_string_lossy(), frame, params)? {
+        return Err(opencv::Error::new(
+            opencv::core::StsError,
+            format!("Unable to write {}", path.display()),
+        ));
+    }
+    Ok(())
+}
+
+// Keeps frames on disk for later review while people are in view
+pub struct SnapshotSaver {
+    dir: PathBuf,
+    min_interval: Duration,
+    // Only the frame where someone enters an empty scene
+    on_change: bool,
+    quality: i32,
+    annotated: bool,
+    metadata: bool,
+    last_saved: Option<Instant>,
+}
+
+// Written next to a snapshot as `<name>.json`
+#[derive(Debug, Serialize)]
+struct SnapshotMetadata<'a> {
+    timestamp: String,
+    count: usize,
+    source: &'a str,
+    width: i32,
+    height: i32,
+    // Whether boxes and overlay are drawn into the image
+    annotated: bool,
+    bounding_boxes: Vec<BoundingBox>,
+}
+
+impl SnapshotSaver {
+    pub fn new(config: &SnapshotsConfig) -> Self {
+        SnapshotSaver {
+            dir: config.dir.clone(),
+            min_interval: Duration::from_secs(config.min_interval_secs),
+            on_change: config.on_change,
+            quality: config.quality,
+            annotated: config.annotated,
+            metadata: config.metadata,
+            last_saved: None,
+        }
+    }
+
+    // Whether to save the frame after drawing on it, otherwise it has to be
+    // kept as captured
+    pub fn annotated(&self) -> bool {
+        self.annotated
+    }
+
+    // True while people are in view, or with `on_change` on a 0 to >0
+    // transition, unless a snapshot was saved within `min_interval`
+    pub fn triggered(&mut self, previous_count: usize, people_count: usize) -> bool {
+        if people_count == 0 || (self.on_change && previous_count > 0) {
+            return false;
+        }
+        if self.last_saved.is_some_and(|at| at.elapsed() < self.min_interval) {
+            return false;
+        }
+        self.last_saved = Some(Instant::now());
+        true
+    }
+
+    // Writes `<dir>/snapshot_<timestamp>_<count>people.jpg` and its metadata
+    pub fn save(&self, frame: &Mat, boxes: &VectorOfRect, source: &str) -> opencv::Result<PathBuf> {
+        create_dir(&self.dir)?;
+        let now = Local::now();
+        let count = boxes.len();
+        let path = self.dir.join(format!(
+            "snapshot_{}_{}people.jpg",
+            now.format("%Y%m%d_%H%M%S%.3f"),
+            count
+        ));
+        let params = Vector::from_slice(&[imgcodecs::IMWRITE_JPEG_QUALITY, self.quality]);
+        write(&path, frame, &params)?;
+
+        if self.metadata {
+            let metadata = SnapshotMetadata {
+                timestamp: now.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Millis, true),
+                count,
+                source,
+                width: frame.cols(),
+                height: frame.rows(),
+                annotated: self.annotated,
+                bounding_boxes: boxes.iter().map(BoundingBox::from).collect(),
+            };
+            let sidecar = path.with_extension("json");
+            let written = serde_json::to_string(&metadata)
+                .map_err(std::io::Error::from)
+                .and_then(|json| fs::write(&sidecar, json));
+            // The image is what matters, a missing sidecar is only logged
+            if let Err(e) = written {
+                log::warn!("Unable to write {}: {}", sidecar.display(), e);
+            }
+        }
+        Ok(path)
+    }
+}
+
+// Largest share of the frame size each downscaling step keeps
+const DOWNSCALE_STEP: f64 = 0.75;
+// Below this width a snapshot isn't worth sending
+const MIN_WIDTH: i32 = 160;
+
+// JPEG of the annotated frame published to the snapshot topic when someone
+// shows up in an empty scene, so dashboards can show who triggered the count
+pub struct SnapshotPublisher {
+    quality: i32,
+    min_interval: Duration,
+    max_bytes: usize,
+    last_sent: Option<Instant>,
+}
+
+impl SnapshotPublisher {
+    // `max_bytes` is the largest payload the MQTT client may send
+    pub fn new(config: &SnapshotPublishConfig, max_bytes: usize) -> Self {
+        SnapshotPublisher {
+            quality: config.quality,
+            min_interval: Duration::from_secs(config.min_interval_secs),
+            max_bytes,
+            last_sent: None,
+        }
+    }
+
+    // True on a 0 to >0 transition, unless a snapshot went out within `min_interval`
+    pub fn triggered(&mut self, previous_count: usize, people_count: usize) -> bool {
+        if previous_count > 0 || people_count == 0 {
+            return false;
+        }
+        if self.last_sent.is_some_and(|at| at.elapsed() < self.min_interval) {
+            return false;
+        }
+        self.last_sent = Some(Instant::now());
+        true
+    }
+
+    // Downscales the frame until the JPEG fits into one MQTT message
+    pub fn encode(&self, frame: &Mat) -> opencv::Result<Vec<u8>> {
+        let params = Vector::from_slice(&[imgcodecs::IMWRITE_JPEG_QUALITY, self.quality]);
+        let mut image = frame.try_clone()?;
+        loop {
+            let mut jpeg = Vector::<u8>::new();
+            imgcodecs::imencode(".jpg", &image, &mut jpeg, &params)?;
+            if jpeg.len() <= self.max_bytes {
+                return Ok(jpeg.to_vec());
+            }
+
+            let width = (image.cols() as f64 * DOWNSCALE_STEP) as i32;
+            let height = (image.rows() as f64 * DOWNSCALE_STEP) as i32;
+            if width < MIN_WIDTH {
+                return Err(opencv::Error::new(
+                    opencv::core::StsError,
+                    format!("snapshot doesn't fit into {} bytes even at {}px wide", self.max_bytes, image.cols()),
+                ));
+            }
+            let mut smaller = Mat::default();
+            imgproc::resize(&image, &mut smaller, Size::new(width, height), 0.0, 0.0, imgproc::INTER_AREA)?;
+            image = smaller;
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// Totals over a video file, printed when it has been processed, see
+// `dry-run` and video file sources
+#[derive(Debug, Default)]
+pub struct Summary {
+    frames: u64,
+    total_count: u64,
+    // Detection passes per people count
+    histogram: BTreeMap<usize, u64>,
+    total_latency: Duration,
+}
+
+impl Summary {
+    pub fn add(&mut self, people_count: usize, latency: Duration) {
+        self.frames += 1;
+        self.total_count += people_count as u64;
+        *self.histogram.entry(people_count).or_default() += 1;
+        self.total_latency += latency;
+    }
+
+    pub fn print(&self) {
+        println!("Frames processed:      {}", self.frames);
+        if self.frames == 0 {
+            return;
+        }
+        let frames = self.frames as f64;
+        println!("Average people count:  {:.2}", self.total_count as f64 / frames);
+        println!(
+            "Maximum people count:  {}",
+            self.histogram.keys().next_back().copied().unwrap_or_default()
+        );
+        println!(
+            "Average detection:     {:.1} ms",
+            self.total_latency.as_secs_f64() * 1000.0 / frames
+        );
+        println!("People per frame:");
+        for (people_count, passes) in &self.histogram {
+            println!(
+                "  {:>3}: {:>8} ({:.1}%)",
+                people_count,
+                passes,
+                *passes as f64 * 100.0 / frames
+            );
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use crate::event::StageTimings;
+
+// Mean duration of one stage since the last `take`
+#[derive(Debug, Default)]
+struct Average {
+    total: Duration,
+    samples: u32,
+}
+
+impl Average {
+    fn add(&mut self, duration: Duration) {
+        self.total += duration;
+        self.samples += 1;
+    }
+
+    // 0 when the stage didn't run, e.g. drawing in headless mode
+    fn ms(&self) -> f32 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+        self.total.as_secs_f32() * 1000.0 / self.samples as f32
+    }
+}
+
+// How long the stages of the main loop take, averaged per stats interval
+// so a slowdown shows up in the stats topic instead of only as a lower FPS
+#[derive(Debug, Default)]
+pub struct StageTimer {
+    detection: Average,
+    draw: Average,
+    publish: Average,
+    display: Average,
+}
+
+impl StageTimer {
+    pub fn new() -> Self {
+        StageTimer::default()
+    }
+
+    // Measured on the detection thread, handed over with the boxes
+    pub fn detection(&mut self, latency: Duration) {
+        self.detection.add(latency);
+    }
+
+    pub fn draw(&mut self, duration: Duration) {
+        self.draw.add(duration);
+    }
+
+    pub fn publish(&mut self, duration: Duration) {
+        self.publish.add(duration);
+    }
+
+    pub fn display(&mut self, duration: Duration) {
+        self.display.add(duration);
+    }
+
+    // Averages since the last call, `capture_ms` comes from the capture thread
+    pub fn take(&mut self, capture_ms: f32) -> StageTimings {
+        let timings = StageTimings::new(
+            capture_ms,
+            self.detection.ms(),
+            self.draw.ms(),
+            self.publish.ms(),
+            self.display.ms(),
+        );
+        *self = StageTimer::default();
+        timings
+    }
+}
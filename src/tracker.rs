@@ -0,0 +1,201 @@
+use opencv::{core::Rect, types::VectorOfRect};
+use std::collections::HashMap;
+
+use crate::config::TrackerConfig;
+
+#[derive(Debug, Clone)]
+pub struct TrackedPerson {
+    pub id: u32,
+    pub bbox: Rect,
+    // Frames since the person was first seen
+    pub age_frames: u32,
+    // Consecutive frames without a matching detection
+    pub missed_frames: u32,
+}
+
+impl TrackedPerson {
+    fn centroid(&self) -> (f32, f32) {
+        centroid(&self.bbox)
+    }
+}
+
+// Gives people stable ids across frames by matching each detection to the
+// nearest known centroid. A track is dropped after `max_missed_frames`
+// frames without a detection, the person gets a new id when they come back.
+pub struct CentroidTracker {
+    tracks: HashMap<u32, TrackedPerson>,
+    next_id: u32,
+    max_distance: f32,
+    max_missed_frames: u32,
+}
+
+impl CentroidTracker {
+    pub fn new(config: &TrackerConfig) -> Self {
+        CentroidTracker {
+            tracks: HashMap::new(),
+            next_id: 1,
+            max_distance: config.max_distance,
+            max_missed_frames: config.max_missed_frames,
+        }
+    }
+
+    // People tracked since start, a person lost for longer than
+    // `max_missed_frames` counts again when they come back
+    pub fn unique_count(&self) -> u64 {
+        u64::from(self.next_id - 1)
+    }
+
+    // Returns the id and box of everyone seen in this frame, ordered by id
+    pub fn update(&mut self, detections: &[Rect]) -> Vec<(usize, Rect)> {
+        // Closest pairs are matched first, each track and detection at most once
+        let mut candidates = Vec::new();
+        for (&id, track) in &self.tracks {
+            let (tx, ty) = track.centroid();
+            for (index, detection) in detections.iter().enumerate() {
+                let (dx, dy) = centroid(detection);
+                let distance = ((tx - dx).powi(2) + (ty - dy).powi(2)).sqrt();
+                if distance <= self.max_distance {
+                    candidates.push((distance, id, index));
+                }
+            }
+        }
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut matched_tracks = Vec::new();
+        let mut matched_detections = vec![false; detections.len()];
+        for (_, id, index) in candidates {
+            if matched_tracks.contains(&id) || matched_detections[index] {
+                continue;
+            }
+            matched_tracks.push(id);
+            matched_detections[index] = true;
+            if let Some(track) = self.tracks.get_mut(&id) {
+                track.bbox = detections[index];
+                track.missed_frames = 0;
+            }
+        }
+
+        for track in self.tracks.values_mut() {
+            track.age_frames += 1;
+            if !matched_tracks.contains(&track.id) {
+                track.missed_frames += 1;
+            }
+        }
+        let max_missed_frames = self.max_missed_frames;
+        self.tracks
+            .retain(|_, track| track.missed_frames <= max_missed_frames);
+
+        for (index, detection) in detections.iter().enumerate() {
+            if matched_detections[index] {
+                continue;
+            }
+            let id = self.next_id;
+            self.next_id += 1;
+            self.tracks.insert(
+                id,
+                TrackedPerson {
+                    id,
+                    bbox: *detection,
+                    age_frames: 1,
+                    missed_frames: 0,
+                },
+            );
+        }
+
+        self.visible()
+            .into_iter()
+            .map(|track| (track.id as usize, track.bbox))
+            .collect()
+    }
+
+    // The people matched by the last `update`, ordered by id
+    pub fn visible(&self) -> Vec<TrackedPerson> {
+        let mut visible: Vec<TrackedPerson> = self
+            .tracks
+            .values()
+            .filter(|track| track.missed_frames == 0)
+            .cloned()
+            .collect();
+        visible.sort_by_key(|track| track.id);
+        visible
+    }
+
+    // `update` for the detector's output, returning the full tracks
+    pub fn track(&mut self, boxes: &VectorOfRect) -> Vec<TrackedPerson> {
+        self.update(&boxes.to_vec())
+            .into_iter()
+            .filter_map(|(id, _)| self.tracks.get(&(id as u32)).cloned())
+            .collect()
+    }
+}
+
+fn centroid(rect: &Rect) -> (f32, f32) {
+    (
+        rect.x as f32 + rect.width as f32 / 2.0,
+        rect.y as f32 + rect.height as f32 / 2.0,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(max_missed_frames: u32) -> CentroidTracker {
+        CentroidTracker::new(&TrackerConfig {
+            max_distance: 80.0,
+            max_missed_frames,
+        })
+    }
+
+    fn person(x: i32) -> Rect {
+        Rect::new(x, 50, 64, 128)
+    }
+
+    #[test]
+    fn moving_box_keeps_its_id() {
+        let mut tracker = tracker(2);
+        for frame in 0..10 {
+            assert_eq!(tracker.update(&[person(20 * frame)]), vec![(1, person(20 * frame))]);
+        }
+        assert_eq!(tracker.unique_count(), 1);
+    }
+
+    #[test]
+    fn two_people_walking_apart_keep_their_ids() {
+        let mut tracker = tracker(2);
+        for frame in 0..5 {
+            let left = person(200 - 30 * frame);
+            let right = person(300 + 30 * frame);
+            // Ids follow the first frame's order, later frames list them the other way round
+            let boxes = if frame == 0 { [left, right] } else { [right, left] };
+            assert_eq!(tracker.update(&boxes), vec![(1, left), (2, right)]);
+        }
+    }
+
+    #[test]
+    fn box_missing_up_to_max_missed_keeps_its_id() {
+        let mut tracker = tracker(2);
+        tracker.update(&[person(0)]);
+        assert!(tracker.update(&[]).is_empty());
+        assert!(tracker.update(&[]).is_empty());
+        assert_eq!(tracker.update(&[person(10)]), vec![(1, person(10))]);
+    }
+
+    #[test]
+    fn box_missing_longer_than_max_missed_gets_a_new_id() {
+        let mut tracker = tracker(2);
+        tracker.update(&[person(0)]);
+        for _ in 0..3 {
+            assert!(tracker.update(&[]).is_empty());
+        }
+        assert_eq!(tracker.update(&[person(10)]), vec![(2, person(10))]);
+        assert_eq!(tracker.unique_count(), 2);
+    }
+
+    #[test]
+    fn box_jumping_further_than_max_distance_gets_a_new_id() {
+        let mut tracker = tracker(2);
+        tracker.update(&[person(0)]);
+        assert_eq!(tracker.update(&[person(200)]), vec![(2, person(200))]);
+    }
+}
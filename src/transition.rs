@@ -0,0 +1,36 @@
+use crate::debounce::Debouncer;
+use crate::event::{TransitionEvent, TransitionKind};
+
+// Turns the per-pass people count into enter/leave events. A new count only
+// counts once it was seen on `stable_passes` detection passes in a row, so a
+// borderline detection flickering between 0 and 1 doesn't fire every frame.
+pub struct TransitionDetector {
+    count: Debouncer,
+    // Last count an event was fired for, the scene starts out empty
+    reported: usize,
+}
+
+impl TransitionDetector {
+    pub fn new(stable_passes: u32) -> Self {
+        TransitionDetector {
+            count: Debouncer::new(stable_passes, 0),
+            reported: 0,
+        }
+    }
+
+    pub fn update(&mut self, count: usize) -> Option<TransitionEvent> {
+        let count = self.count.update(count);
+        if count == self.reported {
+            return None;
+        }
+
+        let kind = if count > self.reported {
+            TransitionKind::Enter
+        } else {
+            TransitionKind::Leave
+        };
+        let delta = count as i64 - self.reported as i64;
+        self.reported = count;
+        Some(TransitionEvent::new(kind, delta, count))
+    }
+}
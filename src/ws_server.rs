@@ -0,0 +1,150 @@
+use futures_util::{SinkExt, StreamExt};
+use hyper::header::{self, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tokio::sync::broadcast;
+use tokio::time::{self, Duration, Instant};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+// Keeps idle connections from being closed by proxies and NAT routers
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PING: &str = r#"{"type":"ping"}"#;
+
+// Page at `GET /` printing every event it receives, to check the server
+// from a browser
+const TEST_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>People detection events</title></head>
+<body>
+<p id="status">Connecting...</p>
+<pre id="events"></pre>
+<script>
+const socket = new WebSocket(`ws://${location.host}/`);
+const events = document.getElementById("events");
+socket.onopen = () => document.getElementById("status").textContent = "Connected";
+socket.onclose = () => document.getElementById("status").textContent = "Disconnected";
+socket.onmessage = (message) => {
+    events.textContent = message.data + "\n" + events.textContent.split("\n").slice(0, 50).join("\n");
+};
+</script>
+</body>
+</html>
+"#;
+
+// Pushes every serialized `DetectionEvent` sent on `events` to all connected
+// WebSocket clients. Each client has its own task, which ends when a send to
+// it fails, so disconnected clients drop out without any bookkeeping.
+pub fn spawn(addr: SocketAddr, events: broadcast::Sender<String>) {
+    tokio::spawn(async move {
+        let server = match Server::try_bind(&addr) {
+            Ok(server) => server,
+            Err(e) => {
+                log::error!("Unable to start WebSocket server on {}: {}", addr, e);
+                return;
+            }
+        };
+        log::info!("Pushing detection events on ws://{}/", addr);
+        let service = make_service_fn(move |_| {
+            let events = events.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let events = events.clone();
+                    async move { Ok::<_, Infallible>(handle(request, events)) }
+                }))
+            }
+        });
+        if let Err(e) = server.serve(service).await {
+            log::error!("WebSocket server failed: {}", e);
+        }
+    });
+}
+
+fn handle(mut request: Request<Body>, events: broadcast::Sender<String>) -> Response<Body> {
+    if request.method() != Method::GET || request.uri().path() != "/" {
+        return status(StatusCode::NOT_FOUND);
+    }
+    if !is_websocket_upgrade(&request) {
+        let mut response = Response::new(Body::from(TEST_PAGE));
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        return response;
+    }
+    let Some(key) = request.headers().get(header::SEC_WEBSOCKET_KEY) else {
+        return status(StatusCode::BAD_REQUEST);
+    };
+    let accept = derive_accept_key(key.as_bytes());
+
+    // Subscribe before answering so no event between handshake and first poll is lost
+    let receiver = events.subscribe();
+    let upgrade = hyper::upgrade::on(&mut request);
+    tokio::spawn(async move {
+        match upgrade.await {
+            Ok(upgraded) => {
+                let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                serve_client(socket, receiver).await;
+            }
+            Err(e) => log::warn!("WebSocket upgrade failed: {}", e),
+        }
+    });
+
+    let mut response = status(StatusCode::SWITCHING_PROTOCOLS);
+    let headers = response.headers_mut();
+    headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    if let Ok(accept) = HeaderValue::from_str(&accept) {
+        headers.insert(header::SEC_WEBSOCKET_ACCEPT, accept);
+    }
+    response
+}
+
+async fn serve_client(mut socket: WebSocketStream<Upgraded>, mut events: broadcast::Receiver<String>) {
+    let mut ping = time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+    loop {
+        let message = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => Message::Text(event),
+                // A slow client skips events rather than holding up the others
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            _ = ping.tick() => Message::Text(PING.to_string()),
+            incoming = socket.next() => match incoming {
+                // Clients have nothing to say, only closing matters
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        };
+        if socket.send(message).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn is_websocket_upgrade(request: &Request<Body>) -> bool {
+    let header_contains = |name: header::HeaderName, value: &str| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|header| header.to_str().ok())
+            .is_some_and(|header| {
+                header
+                    .split(',')
+                    .any(|part| part.trim().eq_ignore_ascii_case(value))
+            })
+    };
+    header_contains(header::CONNECTION, "upgrade") && header_contains(header::UPGRADE, "websocket")
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,53 @@
+use opencv::{core::Rect, types::VectorOfRect};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// Named region of the frame, people are only counted inside zones when at
+// least one is configured
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Zone {
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Zone {
+    pub fn rect(&self) -> Rect {
+        Rect::new(self.x, self.y, self.width, self.height)
+    }
+
+    // A person belongs to a zone when the center of their box lies inside it
+    pub fn contains(&self, person: &Rect) -> bool {
+        let center_x = person.x + person.width / 2;
+        let center_y = person.y + person.height / 2;
+        center_x >= self.x
+            && center_x < self.x + self.width
+            && center_y >= self.y
+            && center_y < self.y + self.height
+    }
+}
+
+// Keeps the boxes inside at least one zone, all of them when there are no zones
+pub fn filter(zones: &[Zone], boxes: &VectorOfRect) -> VectorOfRect {
+    if zones.is_empty() {
+        return boxes.clone();
+    }
+    boxes
+        .iter()
+        .filter(|person| zones.iter().any(|zone| zone.contains(person)))
+        .collect()
+}
+
+// People per zone name, a person in overlapping zones counts for each of them
+pub fn counts(zones: &[Zone], boxes: &VectorOfRect) -> BTreeMap<String, usize> {
+    zones
+        .iter()
+        .map(|zone| {
+            let count = boxes.iter().filter(|person| zone.contains(person)).count();
+            (zone.name.clone(), count)
+        })
+        .collect()
+}